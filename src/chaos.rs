@@ -0,0 +1,183 @@
+use glob::Pattern;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::info;
+
+use crate::Proxy;
+use crate::protocol::{Request, Session};
+use crate::recording::Type_;
+
+const INJECTED: &str = "spy injected fault";
+
+// Faults, which are injected into forwarded connections to see how the clients of rrdcached
+// cope with a slow or misbehaving daemon.
+pub struct Chaos {
+    latency: Option<Duration>,
+    // The chance in percent, that a command stalls its connection for good.
+    stall: f64,
+    drop_after: Option<u64>,
+    error: f64,
+    error_paths: Vec<Pattern>,
+}
+
+impl Chaos {
+    pub fn new(
+        latency: Option<Duration>,
+        stall: Option<f64>,
+        drop_after: Option<u64>,
+        error: Option<f64>,
+        error_paths: &[Pattern],
+    ) -> Option<Self> {
+        if latency.is_none()
+            && stall.is_none()
+            && drop_after.is_none()
+            && error.is_none()
+            && error_paths.is_empty()
+        {
+            return None;
+        }
+        Some(Self {
+            latency,
+            stall: stall.unwrap_or_default(),
+            drop_after,
+            error: error.unwrap_or_default(),
+            error_paths: error_paths.to_vec(),
+        })
+    }
+
+    fn fails(&self, request: &Request) -> bool {
+        let path = request
+            .path
+            .as_ref()
+            .is_some_and(|path| self.error_paths.iter().any(|p| p.matches(path)));
+        path || chance(self.error)
+    }
+}
+
+fn chance(percent: f64) -> bool {
+    percent > 0.0 && rand::random_bool(percent / 100.0)
+}
+
+// Commands, which are never failed or stalled, as they change the framing of the session.
+fn is_faultable(request: &Request) -> bool {
+    request.answered && !matches!(request.command.as_str(), "" | "BATCH" | "." | "QUIT")
+}
+
+// A command, which awaits its response. Injected errors are queued behind the commands sent
+// upstream before them, so the client receives the responses in order.
+enum Slot {
+    Upstream { command: String, stall: bool },
+    Injected(Vec<u8>),
+}
+
+// Applies the faults to the traffic of a single connection. Traffic is passed on in complete
+// lines, so that commands can be answered in place of the upstream.
+pub struct Faults<'a> {
+    id: u64,
+    proxy: &'a Proxy,
+    chaos: &'a Chaos,
+    session: Session,
+    slots: VecDeque<Slot>,
+    relayed: u64,
+    stalled: bool,
+    pub dropped: bool,
+}
+
+impl<'a> Faults<'a> {
+    pub fn new(id: u64, proxy: &'a Proxy) -> Option<Self> {
+        Some(Self {
+            id,
+            proxy,
+            chaos: proxy.chaos.as_ref()?,
+            session: Session::default(),
+            slots: VecDeque::new(),
+            relayed: 0,
+            stalled: false,
+            dropped: false,
+        })
+    }
+
+    fn inject(&self, message: String) {
+        info!(id = self.id, "injected {message}");
+        self.proxy.recorder.text(Type_::Fault, self.id, message);
+    }
+
+    // Returns the bytes for the upstream and the injected responses for the client.
+    pub fn send(&mut self, bytes: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut upstream = Vec::new();
+        let mut client = Vec::new();
+        for request in self.session.requests(bytes) {
+            let faultable = is_faultable(&request);
+            if faultable && self.chaos.fails(&request) {
+                self.inject(format!("error for {}", request.command));
+                let response = format!("-1 {INJECTED}\n").into_bytes();
+                match self.slots.is_empty() {
+                    true => client.extend(response),
+                    false => self.slots.push_back(Slot::Injected(response)),
+                }
+                continue;
+            }
+            if request.answered {
+                self.slots.push_back(Slot::Upstream {
+                    command: request.command,
+                    stall: faultable && chance(self.chaos.stall),
+                });
+            }
+            upstream.extend_from_slice(&request.line);
+        }
+        if self.stalled {
+            client.clear();
+        }
+        (self.limit(upstream), self.limit(client))
+    }
+
+    // Returns the bytes for the client.
+    pub async fn recv(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut client = Vec::new();
+        for response in self.session.responses(bytes) {
+            if let Some(Slot::Upstream {
+                command,
+                stall: true,
+            }) = self.slots.pop_front()
+            {
+                self.inject(format!("stall of {command}"));
+                self.stalled = true;
+            }
+            client.extend(response.bytes);
+            while let Some(Slot::Injected(_)) = self.slots.front() {
+                if let Some(Slot::Injected(response)) = self.slots.pop_front() {
+                    client.extend(response);
+                }
+            }
+        }
+        if self.stalled {
+            client.clear();
+        }
+        if let Some(latency) = self.chaos.latency
+            && !client.is_empty()
+        {
+            self.inject(format!("latency of {latency:?}"));
+            sleep(latency).await;
+        }
+        self.limit(client)
+    }
+
+    // Cuts the connection once the configured number of bytes has been relayed.
+    fn limit(&mut self, mut bytes: Vec<u8>) -> Vec<u8> {
+        let Some(drop_after) = self.chaos.drop_after else {
+            return bytes;
+        };
+        if self.dropped {
+            return Vec::new();
+        }
+        let remaining = drop_after - self.relayed;
+        if bytes.len() as u64 >= remaining {
+            bytes.truncate(remaining as usize);
+            self.inject(format!("drop after {drop_after} bytes"));
+            self.dropped = true;
+        }
+        self.relayed += bytes.len() as u64;
+        bytes
+    }
+}
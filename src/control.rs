@@ -0,0 +1,390 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+use crate::net;
+use crate::protocol::COMMANDS;
+use crate::recording::Type_;
+use crate::{Proxy, decipher};
+
+// The traffic of a connection. It is counted by the connection itself, without a lock.
+#[derive(Default)]
+pub struct Traffic {
+    sent: AtomicU64,
+    received: AtomicU64,
+    commands: AtomicU64,
+}
+
+impl Traffic {
+    pub fn sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn add(&self, other: &Traffic) {
+        self.sent(other.sent.load(Ordering::Relaxed) as usize);
+        self.received(other.received.load(Ordering::Relaxed) as usize);
+        let commands = other.commands.load(Ordering::Relaxed);
+        self.commands.fetch_add(commands, Ordering::Relaxed);
+    }
+}
+
+struct Connection {
+    client: String,
+    traffic: Arc<Traffic>,
+}
+
+#[derive(Default, Clone)]
+pub struct Command {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+struct CommandCounter {
+    count: AtomicU64,
+    bytes: AtomicU64,
+}
+
+// Commands, which rrdcached doesn't know, are counted together, so that a client can't grow
+// the counters, e.g., by sending garbage.
+const OTHER: &str = "other";
+
+// Sums over all connections, as exported to Prometheus.
+pub struct Totals {
+    pub accepted: u64,
+    pub open: usize,
+    pub sent: u64,
+    pub received: u64,
+    pub commands: Vec<(&'static str, Command)>,
+    pub upstream_up: bool,
+    pub upstream_errors: u64,
+}
+
+#[derive(Default)]
+enum Upstream {
+    #[default]
+    Unknown,
+    Up(DateTime<Utc>),
+    Down(DateTime<Utc>, String),
+}
+
+// What changes when connections are opened and closed, or the upstream comes and goes.
+#[derive(Default)]
+struct Counters {
+    connections: BTreeMap<u64, Connection>,
+    upstream: Upstream,
+}
+
+// What `spy status` and `spy stats` report. Unlike the recording, the counters cover all
+// traffic, also while recording is paused or filtered.
+pub struct Stats {
+    status: String,
+    started: Instant,
+    accepted: AtomicU64,
+    upstream_errors: AtomicU64,
+    closed: Traffic,
+    commands: [CommandCounter; COMMANDS.len() + 1],
+    counters: Mutex<Counters>,
+}
+
+impl Stats {
+    pub fn new(status: String) -> Self {
+        Self {
+            status,
+            started: Instant::now(),
+            accepted: AtomicU64::default(),
+            upstream_errors: AtomicU64::default(),
+            closed: Traffic::default(),
+            commands: Default::default(),
+            counters: Mutex::default(),
+        }
+    }
+
+    fn counters(&self) -> std::sync::MutexGuard<'_, Counters> {
+        self.counters.lock().unwrap()
+    }
+
+    pub fn open(&self, id: u64, client: &str) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        let connection = Connection {
+            client: client.into(),
+            traffic: Arc::default(),
+        };
+        self.counters().connections.insert(id, connection);
+    }
+
+    pub fn close(&self, id: u64) {
+        let mut counters = self.counters();
+        if let Some(connection) = counters.connections.remove(&id) {
+            self.closed.add(&connection.traffic);
+        }
+    }
+
+    // The counters of an open connection, which it updates as it goes.
+    pub fn traffic(&self, id: u64) -> Arc<Traffic> {
+        match self.counters().connections.get(&id) {
+            Some(connection) => connection.traffic.clone(),
+            None => Arc::default(),
+        }
+    }
+
+    pub fn command(&self, traffic: &Traffic, command: &str, bytes: usize) {
+        traffic.commands.fetch_add(1, Ordering::Relaxed);
+        let index = COMMANDS.iter().position(|&known| known == command);
+        let counter = &self.commands[index.unwrap_or(COMMANDS.len())];
+        counter.count.fetch_add(1, Ordering::Relaxed);
+        counter.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn upstream_up(&self) {
+        self.counters().upstream = Upstream::Up(Utc::now());
+    }
+
+    pub fn upstream_down(&self, e: &io::Error) {
+        let mut counters = self.counters();
+        let since = match counters.upstream {
+            Upstream::Down(since, _) => since,
+            _ => Utc::now(),
+        };
+        counters.upstream = Upstream::Down(since, e.to_string());
+        self.upstream_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The commands seen so far, in the order of their names.
+    fn commands(&self) -> Vec<(&'static str, Command)> {
+        let names = COMMANDS.iter().chain([&OTHER]);
+        names
+            .zip(&self.commands)
+            .map(|(&name, counter)| {
+                let count = counter.count.load(Ordering::Relaxed);
+                let bytes = counter.bytes.load(Ordering::Relaxed);
+                (name, Command { count, bytes })
+            })
+            .filter(|(_, command)| command.count > 0)
+            .collect()
+    }
+
+    pub fn totals(&self) -> Totals {
+        let counters = self.counters();
+        let active = counters.connections.values().map(|c| &*c.traffic);
+        let traffic = active.chain([&self.closed]);
+        let (sent, received) = traffic.fold((0, 0), |(sent, received), traffic| {
+            (
+                sent + traffic.sent.load(Ordering::Relaxed),
+                received + traffic.received.load(Ordering::Relaxed),
+            )
+        });
+        Totals {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            open: counters.connections.len(),
+            sent,
+            received,
+            commands: self.commands(),
+            upstream_up: matches!(counters.upstream, Upstream::Up(_)),
+            upstream_errors: self.upstream_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// The control socket lives next to the pidfile, e.g., `spy.pid.control` for `spy.pid`. The name
+// can't clash with a socket of rrdcached, whose pidfile may well be next to it.
+pub fn path(pidfile: &Path) -> PathBuf {
+    let mut path = pidfile.as_os_str().to_owned();
+    path.push(".control");
+    path.into()
+}
+
+fn timestamp(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn status(proxy: &Proxy) -> String {
+    let stats = &proxy.stats;
+    let counters = stats.counters();
+    let uptime = stats.started.elapsed().as_secs();
+    let mut text = String::new();
+    writeln!(
+        text,
+        "pid {}, up {}h {}m {}s",
+        std::process::id(),
+        uptime / 3600,
+        uptime / 60 % 60,
+        uptime % 60
+    )
+    .unwrap();
+    writeln!(text, "{}", stats.status).unwrap();
+    let recording = match proxy.recorder.recording() {
+        true => "on",
+        false => "paused",
+    };
+    let dropped = proxy.recorder.dropped();
+    writeln!(text, "recording {recording}, {dropped} record(s) dropped").unwrap();
+    writeln!(
+        text,
+        "connections: {} active, {} accepted",
+        counters.connections.len(),
+        stats.accepted.load(Ordering::Relaxed)
+    )
+    .unwrap();
+    let upstream = match &counters.upstream {
+        Upstream::Unknown => "not connected yet".into(),
+        Upstream::Up(since) => format!("connected at {}", timestamp(since)),
+        Upstream::Down(since, e) => format!("unavailable since {}: {e}", timestamp(since)),
+    };
+    writeln!(text, "upstream: {upstream}").unwrap();
+    text
+}
+
+fn stats(proxy: &Proxy) -> String {
+    let counters = proxy.stats.counters();
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let mut text = String::new();
+    writeln!(
+        text,
+        "{:<12} {:<24} {:>12} {:>12} {:>12}",
+        "connection", "client", "sent", "received", "commands"
+    )
+    .unwrap();
+    let rows = counters
+        .connections
+        .iter()
+        .map(|(id, connection)| {
+            (
+                id.to_string(),
+                connection.client.as_str(),
+                &*connection.traffic,
+            )
+        })
+        .chain([("closed".into(), "", &proxy.stats.closed)]);
+    for (connection, client, traffic) in rows {
+        writeln!(
+            text,
+            "{:<12} {:<24} {:>12} {:>12} {:>12}",
+            connection,
+            client,
+            load(&traffic.sent),
+            load(&traffic.received),
+            load(&traffic.commands)
+        )
+        .unwrap();
+    }
+    writeln!(text, "{:<12} {:>12} {:>12}", "command", "count", "bytes").unwrap();
+    for (name, command) in proxy.stats.commands() {
+        writeln!(
+            text,
+            "{:<12} {:>12} {:>12}",
+            name, command.count, command.bytes
+        )
+        .unwrap();
+    }
+    text
+}
+
+// Streams the traffic the way `spy decipher` shows it, until the client goes away.
+async fn tail(
+    token: &CancellationToken,
+    reader: &mut BufReader<tokio::net::unix::OwnedReadHalf>,
+    writer: &mut tokio::net::unix::OwnedWriteHalf,
+    proxy: &Proxy,
+) -> io::Result<()> {
+    let mut live = proxy.recorder.subscribe();
+    let mut buf = [0u8; 64];
+    loop {
+        let text = tokio::select! {
+            record = live.recv() => match record {
+                Ok(record) if record.type_ == Type_::Latency => continue,
+                Ok(record) => decipher(&record),
+                Err(RecvError::Lagged(skipped)) => format!("-- skipped {skipped} event(s)\n"),
+                Err(RecvError::Closed) => break,
+            },
+            read = reader.read(&mut buf) => match read? {
+                0 => break,
+                _ => continue,
+            },
+            _ = token.cancelled() => break,
+        };
+        writer.write_all(text.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+async fn handle(token: CancellationToken, stream: UnixStream, proxy: Arc<Proxy>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut request = String::new();
+    reader.read_line(&mut request).await?;
+    debug!("control request {}", request.trim_end());
+    match request.trim().to_ascii_uppercase().as_str() {
+        "STATUS" => writer.write_all(status(&proxy).as_bytes()).await?,
+        "STATS" => writer.write_all(stats(&proxy).as_bytes()).await?,
+        "TAIL" => tail(&token, &mut reader, &mut writer, &proxy).await?,
+        _ => writer.write_all(b"-1 unknown request\n").await?,
+    }
+    writer.shutdown().await
+}
+
+// Answers `spy status`, `spy stats` and `spy tail`. The socket gets the default mode, so only
+// the user running spy (and root) can connect.
+pub async fn serve(token: CancellationToken, path: PathBuf, proxy: Arc<Proxy>) {
+    if let Err(e) = net::remove_stale(&path) {
+        error!("could not take over control socket {}: {e}", path.display());
+        return;
+    }
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("could not bind control socket {}: {e:?}", path.display());
+            return;
+        }
+    };
+    info!("control socket at {}", path.display());
+    let mut set = JoinSet::new();
+    loop {
+        let stream = tokio::select! {
+            accept = listener.accept() => match accept {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("could not accept control connection: {e:?}");
+                    continue;
+                }
+            },
+            _ = token.cancelled() => break,
+        };
+        let (token, proxy) = (token.clone(), proxy.clone());
+        set.spawn(async move {
+            if let Err(e) = handle(token, stream, proxy).await {
+                debug!("control connection failed: {e:?}");
+            }
+        });
+    }
+    set.join_all().await;
+    if let Err(e) = fs::remove_file(&path) {
+        error!("could not remove control socket {}: {e:?}", path.display());
+    }
+}
+
+// Sends a request to a running spy and copies the answer to stdout.
+pub fn query(pidfile: &Path, request: &str) -> io::Result<()> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path(pidfile))?;
+    stream.write_all(format!("{request}\n").as_bytes())?;
+    match io::copy(&mut stream, &mut io::stdout()) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result.map(|_| ()),
+    }
+}
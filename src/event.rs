@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Recordings from before the metadata record were all taken on this site.
+pub const DEFAULT_RRD_DIR: &str = "/opt/omd/sites/prod/var/check_mk/rrd";
+
+// The kinds of records. Every part of a recording starts with `meta`, which describes the
+// capture. A connection starts with `open`, which names the client, and ends with
+// `close`, which gives the reason. In between, `connect` notes the attempts to reach the
+// upstream, `send`/`recv` carry the traffic as base64, and `error`, `latency` and `fault` are
+// what they say. Recordings from before `open`/`close` only have `connect` and an empty `send`
+// at EOF.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Type_ {
+    Open = 7,
+    Send = 1,
+    Recv = 2,
+    Connect = 3,
+    Error = 4,
+    Latency = 5,
+    Fault = 6,
+    Close = 8,
+    Meta = 9,
+}
+
+impl Type_ {
+    const ALL: [Self; 9] = [
+        Self::Send,
+        Self::Recv,
+        Self::Connect,
+        Self::Error,
+        Self::Latency,
+        Self::Fault,
+        Self::Open,
+        Self::Close,
+        Self::Meta,
+    ];
+
+    // The number identifying the type in binary recordings.
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|type_| type_.code() == code)
+    }
+
+    // Whether the message is traffic, which JSON recordings encode as base64.
+    pub fn is_traffic(self) -> bool {
+        matches!(self, Self::Send | Self::Recv)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Fields {
+    pub type_: Type_,
+    pub id: u64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_us: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<Peer>,
+}
+
+// The process on the client side of a unix socket, taken from SO_PEERCRED and /proc.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Peer {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<i32>,
+    pub uid: u32,
+    pub gid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exe: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmdline: Option<String>,
+}
+
+// Interpreters, whose clients are told apart by the script they run. Versions are ignored, e.g.,
+// `python3.12` is `python`.
+const INTERPRETERS: [&str; 7] = ["perl", "python", "ruby", "php", "node", "sh", "bash"];
+
+fn basename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+impl Peer {
+    // The file name of the executable, e.g., `cmc`, or of the script an interpreter runs, e.g.,
+    // `process_perfdata.pl` instead of `perl` and `omd` instead of `python3`.
+    pub fn program(&self) -> &str {
+        let exe = basename(self.exe.as_deref().unwrap_or("unknown"));
+        self.script(exe).unwrap_or(exe)
+    }
+
+    fn script(&self, exe: &str) -> Option<&str> {
+        let interpreter = exe.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+        if !INTERPRETERS.contains(&interpreter) {
+            return None;
+        }
+        let mut args = self.cmdline.as_deref()?.split(' ').skip(1);
+        while let Some(arg) = args.next() {
+            match arg {
+                "-m" => return args.next(),
+                "-c" | "-e" | "-E" => return None,
+                _ if arg.starts_with('-') => (),
+                _ => return Some(basename(arg)),
+            }
+        }
+        None
+    }
+}
+
+// One line of the recording, laid out like the output of the tracing JSON formatter.
+#[derive(Deserialize, Serialize)]
+pub struct Log {
+    pub timestamp: String,
+    pub fields: Fields,
+}
+
+// One line of a recording of `forward.py`, which spy replaced. The id is a string, the message
+// is always base64 and the time is in seconds since the epoch.
+#[derive(Deserialize, Serialize)]
+pub struct Flat {
+    pub id: String,
+    pub type_: Type_,
+    pub message: String,
+    pub time: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_us: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<Peer>,
+}
+
+// A line of either layout.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Line {
+    Log(Log),
+    Flat(Flat),
+}
+
+// Where a recording was taken. It is stored as JSON in the message of the `meta` record, which
+// has the id 0.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Metadata {
+    pub hostname: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site: Option<String>,
+    pub socket: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rrd_dir: Option<String>,
+    pub started: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+}
+
+impl Metadata {
+    // Moves a path below the RRD directory of the recorded site below `to` instead.
+    pub fn rewrite(rrd_dir: Option<&str>, path: &str, to: &str) -> String {
+        match rrd_dir {
+            Some(rrd_dir) => path.replace(rrd_dir.trim_end_matches('/'), to),
+            None => path.into(),
+        }
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "recorded at {} on {}", self.socket, self.hostname)?;
+        if let Some(site) = &self.site {
+            write!(f, ", site {site}")?;
+        }
+        if let Some(rrd_dir) = &self.rrd_dir {
+            write!(f, ", rrds in {rrd_dir}")?;
+        }
+        write!(f, ", started {} by spy {}", self.started, self.version)?;
+        if !self.labels.is_empty() {
+            write!(f, ", labels {}", self.labels.join(", "))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,673 @@
+use base64::prelude::*;
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use serde_json::{from_str, to_writer};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use tracing::{error, info};
+
+use crate::event::{Fields, Flat, Line, Log, Metadata, Peer, Type_};
+use crate::index;
+
+// A binary recording starts with the magic and the version, followed by a zstd stream of
+// records. Each record is its length as u32 followed by the timestamp in µs as i64, the type
+// code, the connection id as u64, flags, the latency in µs as u64 if flag 1 is set, the length
+// of the peer as u32 and the peer as JSON if flag 2 is set, and the message up to the end.
+// Numbers are little endian.
+const MAGIC: &[u8] = b"SPYREC";
+const VERSION: u8 = 1;
+const HAS_LATENCY: u8 = 1;
+const HAS_PEER: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    Json,
+    Binary,
+    // The JSON lines of `forward.py`, which JSON readers take as well.
+    Flat,
+}
+
+pub fn header() -> Vec<u8> {
+    [MAGIC, &[VERSION]].concat()
+}
+
+// A record as read from either format, with the traffic decoded.
+#[derive(Clone)]
+pub struct Record {
+    pub timestamp: DateTime<Utc>,
+    pub type_: Type_,
+    pub id: u64,
+    pub message: Vec<u8>,
+    pub latency_us: Option<u64>,
+    pub peer: Option<Peer>,
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl Record {
+    pub fn new(type_: Type_, id: u64, message: Vec<u8>) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            type_,
+            id,
+            message,
+            latency_us: None,
+            peer: None,
+        }
+    }
+
+    // The message of records other than traffic.
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.message)
+    }
+
+    pub fn meta(metadata: &Metadata) -> Self {
+        Self::new(Type_::Meta, 0, serde_json::to_vec(metadata).unwrap())
+    }
+
+    // The metadata of a `meta` record.
+    pub fn metadata(&self) -> Option<Metadata> {
+        match self.type_ {
+            Type_::Meta => serde_json::from_slice(&self.message).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn from_log(log: Log) -> io::Result<Self> {
+        let fields = log.fields;
+        let message = match fields.type_.is_traffic() {
+            true => BASE64_STANDARD.decode(&fields.message).map_err(invalid)?,
+            false => fields.message.into_bytes(),
+        };
+        Ok(Self {
+            timestamp: DateTime::parse_from_rfc3339(&log.timestamp)
+                .map_err(invalid)?
+                .to_utc(),
+            type_: fields.type_,
+            id: fields.id,
+            message,
+            latency_us: fields.latency_us,
+            peer: fields.peer,
+        })
+    }
+
+    pub fn from_flat(flat: Flat) -> io::Result<Self> {
+        let micros = (flat.time * 1e6).round() as i64;
+        Ok(Self {
+            timestamp: DateTime::from_timestamp_micros(micros)
+                .ok_or_else(|| invalid("invalid time"))?,
+            type_: flat.type_,
+            id: flat.id.parse().map_err(invalid)?,
+            message: BASE64_STANDARD.decode(&flat.message).map_err(invalid)?,
+            latency_us: flat.latency_us,
+            peer: flat.peer,
+        })
+    }
+
+    pub fn to_flat(&self) -> Flat {
+        Flat {
+            id: self.id.to_string(),
+            type_: self.type_,
+            message: BASE64_STANDARD.encode(&self.message),
+            time: self.timestamp.timestamp_micros() as f64 / 1e6,
+            latency_us: self.latency_us,
+            peer: self.peer.clone(),
+        }
+    }
+
+    pub fn to_log(&self) -> Log {
+        let message = match self.type_.is_traffic() {
+            true => BASE64_STANDARD.encode(&self.message),
+            false => self.text().into_owned(),
+        };
+        Log {
+            timestamp: self.timestamp.to_rfc3339_opts(SecondsFormat::Micros, true),
+            fields: Fields {
+                type_: self.type_,
+                id: self.id,
+                message,
+                latency_us: self.latency_us,
+                peer: self.peer.clone(),
+            },
+        }
+    }
+
+    // Appends the record to `buf`, i.e., a line of JSON or a length-prefixed binary record,
+    // which is compressed by the writer.
+    pub fn encode(&self, format: Format, buf: &mut Vec<u8>) {
+        match format {
+            Format::Json => {
+                to_writer(&mut *buf, &self.to_log()).unwrap();
+                buf.push(b'\n');
+            }
+            Format::Flat => {
+                to_writer(&mut *buf, &self.to_flat()).unwrap();
+                buf.push(b'\n');
+            }
+            Format::Binary => {
+                let start = buf.len();
+                buf.extend_from_slice(&[0; 4]);
+                buf.extend_from_slice(&self.timestamp.timestamp_micros().to_le_bytes());
+                buf.push(self.type_.code());
+                buf.extend_from_slice(&self.id.to_le_bytes());
+                let mut flags = 0;
+                if self.latency_us.is_some() {
+                    flags |= HAS_LATENCY;
+                }
+                if self.peer.is_some() {
+                    flags |= HAS_PEER;
+                }
+                buf.push(flags);
+                if let Some(latency_us) = self.latency_us {
+                    buf.extend_from_slice(&latency_us.to_le_bytes());
+                }
+                if let Some(peer) = &self.peer {
+                    let peer = serde_json::to_vec(peer).unwrap();
+                    buf.extend_from_slice(&(peer.len() as u32).to_le_bytes());
+                    buf.extend_from_slice(&peer);
+                }
+                buf.extend_from_slice(&self.message);
+                let len = (buf.len() - start - 4) as u32;
+                buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+            }
+        }
+    }
+
+    fn decode(body: &[u8]) -> io::Result<Self> {
+        let mut rest = body;
+        let mut take = |n: usize| -> io::Result<&[u8]> {
+            if rest.len() < n {
+                return Err(invalid("truncated record"));
+            }
+            let (taken, remaining) = rest.split_at(n);
+            rest = remaining;
+            Ok(taken)
+        };
+        let u64 = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+        let micros = i64::from_le_bytes(take(8)?.try_into().unwrap());
+        let code = take(1)?[0];
+        let id = u64(take(8)?);
+        let flags = take(1)?[0];
+        let latency_us = match flags & HAS_LATENCY {
+            0 => None,
+            _ => Some(u64(take(8)?)),
+        };
+        let peer = match flags & HAS_PEER {
+            0 => None,
+            _ => {
+                let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+                Some(serde_json::from_slice(take(len)?).map_err(invalid)?)
+            }
+        };
+        Ok(Self {
+            timestamp: DateTime::from_timestamp_micros(micros)
+                .ok_or_else(|| invalid("invalid timestamp"))?,
+            type_: Type_::from_code(code).ok_or_else(|| invalid(format!("unknown type {code}")))?,
+            id,
+            message: rest.to_vec(),
+            latency_us,
+            peer,
+        })
+    }
+}
+
+// Reads the records of a single file in either format.
+pub enum Reader {
+    Json(io::Lines<BufReader<fs::File>>),
+    Binary(Box<zstd::Decoder<'static, BufReader<fs::File>>>),
+    Done,
+}
+
+// Tells the format of a file from its first bytes. Anything, which isn't binary, is read as
+// JSON lines of either layout.
+pub fn detect(reader: &mut BufReader<fs::File>) -> io::Result<Format> {
+    if !reader.fill_buf()?.starts_with(MAGIC) {
+        return Ok(Format::Json);
+    }
+    reader.consume(MAGIC.len());
+    let mut version = [0u8];
+    reader.read_exact(&mut version)?;
+    if version[0] != VERSION {
+        return Err(invalid(format!(
+            "unsupported version {} of binary recording",
+            version[0]
+        )));
+    }
+    Ok(Format::Binary)
+}
+
+// Tells the layout of a line of JSON.
+pub fn layout(line: &str) -> Format {
+    match from_str(line) {
+        Ok(Line::Flat(_)) => Format::Flat,
+        _ => Format::Json,
+    }
+}
+
+pub fn read(path: &Path) -> io::Result<Reader> {
+    read_at(path, 0)
+}
+
+// Reads from `offset` on, which is the start of a line or of a zstd frame, as in the index.
+pub fn read_at(path: &Path, offset: u64) -> io::Result<Reader> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let format = detect(&mut reader)?;
+    if offset > 0 {
+        reader.seek(SeekFrom::Start(offset))?;
+    }
+    Ok(match format {
+        Format::Json | Format::Flat => Reader::Json(reader.lines()),
+        Format::Binary => Reader::Binary(Box::new(zstd::Decoder::with_buffer(reader)?)),
+    })
+}
+
+// Reads the length of the next record, or nothing at the end of the recording.
+fn read_len(reader: &mut impl Read) -> io::Result<Option<usize>> {
+    let mut len = [0u8; 4];
+    let mut filled = 0;
+    while filled < len.len() {
+        match reader.read(&mut len[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(invalid("truncated record")),
+            n => filled += n,
+        }
+    }
+    Ok(Some(u32::from_le_bytes(len) as usize))
+}
+
+pub fn parse_line(line: &str) -> io::Result<Record> {
+    match from_str(line).map_err(invalid)? {
+        Line::Log(log) => Record::from_log(log),
+        Line::Flat(flat) => Record::from_flat(flat),
+    }
+}
+
+// Reads the next record of a zstd stream, or nothing at its end.
+pub fn read_binary(reader: &mut impl Read) -> io::Result<Option<Record>> {
+    match read_len(reader)? {
+        Some(len) => {
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body)?;
+            Record::decode(&body).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+impl Reader {
+    fn next_record(&mut self) -> io::Result<Option<Record>> {
+        match self {
+            Reader::Json(lines) => match lines.next() {
+                Some(line) => parse_line(&line?).map(Some),
+                None => Ok(None),
+            },
+            Reader::Binary(decoder) => read_binary(decoder),
+            Reader::Done => Ok(None),
+        }
+    }
+}
+
+impl Iterator for Reader {
+    type Item = io::Result<Record>;
+
+    // Stops after the first error, e.g., at the end of a recording, which was cut off.
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.next_record();
+        if !matches!(next, Ok(Some(_))) {
+            *self = Reader::Done;
+        }
+        next.transpose()
+    }
+}
+
+// The records taken between `--from` and `--to`, both RFC 3339, e.g., `2025-05-22T09:00:00Z`.
+#[derive(clap::Args, Clone, Copy, Debug, Default)]
+pub struct Window {
+    #[clap(long)]
+    pub from: Option<DateTime<Utc>>,
+    #[clap(long)]
+    pub to: Option<DateTime<Utc>>,
+}
+
+// Records are written slightly out of order, so reading goes on for a while after `--to`.
+const GRACE: TimeDelta = TimeDelta::minutes(1);
+
+impl Window {
+    fn after_start(&self, record: &Record) -> bool {
+        self.from.is_none_or(|from| record.timestamp >= from)
+    }
+
+    fn before_end(&self, record: &Record) -> bool {
+        self.to.is_none_or(|to| record.timestamp <= to)
+    }
+
+    fn before_grace(&self, record: &Record) -> bool {
+        self.to.is_none_or(|to| record.timestamp <= to + GRACE)
+    }
+}
+
+// The `open` records of connections, which are found in the chunk at `opened.offset`.
+fn opens(path: &Path, opened: &index::Opened) -> Vec<Record> {
+    let Ok(reader) = read_at(path, opened.offset) else {
+        return Vec::new();
+    };
+    let mut ids: HashSet<u64> = opened.ids.iter().copied().collect();
+    let mut opens = Vec::new();
+    for record in reader.map_while(Result::ok) {
+        if record.timestamp > opened.until + GRACE || ids.is_empty() {
+            break;
+        }
+        if record.type_ == Type_::Open && ids.remove(&record.id) {
+            opens.push(record);
+        }
+    }
+    opens
+}
+
+// The records of a single file within the window. With `--from`, reading starts at the chunk
+// found in the index, but the metadata at the start of the file is kept, and so are the `open`
+// records of connections, which are still active when the window starts. Errors are logged and
+// end the recording.
+pub fn records(path: &Path, window: Window) -> impl Iterator<Item = Record> + use<> {
+    let name = path.display().to_string();
+    let start = window
+        .from
+        .map_or_else(index::Start::default, |from| index::start(path, from));
+    let meta = match start.offset {
+        0 => None,
+        _ => read(path).ok().and_then(|mut reader| reader.next()?.ok()),
+    };
+    let meta = meta.filter(|record| record.type_ == Type_::Meta);
+    // The `open` records before the window, which are passed on with its first record, unless
+    // their connection is closed before.
+    let mut active: BTreeMap<u64, Record> = (start.active.iter())
+        .flat_map(|opened| opens(path, opened))
+        .map(|record| (record.id, record))
+        .collect();
+    let reader = match read_at(path, start.offset) {
+        Ok(reader) => reader,
+        Err(e) => {
+            error!("could not read {name}: {e}");
+            Reader::Done
+        }
+    };
+    let records = reader.map_while(move |record| match record {
+        Ok(record) => Some(record),
+        Err(e) => {
+            error!("could not read {name}: {e}");
+            None
+        }
+    });
+    meta.into_iter()
+        .chain(records)
+        .take_while(move |record| window.before_grace(record))
+        .filter(move |record| window.before_end(record))
+        .flat_map(move |record| {
+            if record.type_ == Type_::Meta {
+                return vec![record];
+            }
+            if !window.after_start(&record) {
+                match record.type_ {
+                    Type_::Open => drop(active.insert(record.id, record)),
+                    Type_::Close => drop(active.remove(&record.id)),
+                    _ => (),
+                }
+                return Vec::new();
+            }
+            let mut records: Vec<Record> = std::mem::take(&mut active).into_values().collect();
+            records.push(record);
+            records
+        })
+}
+
+pub fn first_timestamp(path: &Path) -> Option<DateTime<Utc>> {
+    let record = read(path).ok()?.next()?.ok()?;
+    Some(record.timestamp)
+}
+
+// Resolves a recording, which may be a single file, a directory or a glob of rotated parts.
+// Parts are ordered by their first record, which works for both spy's timestamped names and
+// the numbered names of logrotate.
+pub fn parts(input: &Path) -> Vec<PathBuf> {
+    let pattern = input.to_string_lossy();
+    let candidates: Vec<PathBuf> = if pattern.contains(['*', '?', '[']) {
+        glob::glob(&pattern)
+            .unwrap()
+            .filter_map(Result::ok)
+            .collect()
+    } else if input.is_dir() {
+        fs::read_dir(input)
+            .unwrap()
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.is_file())
+            .collect()
+    } else {
+        return vec![input.into()];
+    };
+    let mut parts: Vec<(DateTime<Utc>, PathBuf)> = candidates
+        .into_iter()
+        .filter(|path| !index::is_index(path))
+        .filter_map(|path| Some((first_timestamp(&path)?, path)))
+        .collect();
+    parts.sort();
+    parts.into_iter().map(|(_, path)| path).collect()
+}
+
+// The records of all parts of a recording within the window, in either format.
+pub fn recording(input: &Path, window: Window) -> impl Iterator<Item = Record> {
+    parts(input).into_iter().flat_map(move |path| {
+        info!("reading {}", path.to_string_lossy());
+        records(&path, window)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{Chunk, Entry};
+    use std::io::Write;
+
+    fn record(secs: i64, type_: Type_, id: u64, message: &[u8]) -> Record {
+        Record {
+            timestamp: DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap(),
+            type_,
+            id,
+            message: message.to_vec(),
+            latency_us: None,
+            peer: None,
+        }
+    }
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("spy-{}-{name}", std::process::id()))
+    }
+
+    fn json(records: &[Record]) -> String {
+        let logs: Vec<Log> = records.iter().map(Record::to_log).collect();
+        serde_json::to_string(&logs).unwrap()
+    }
+
+    fn seconds(records: impl Iterator<Item = Record>) -> Vec<(Type_, u64, i64)> {
+        records
+            .map(|record| {
+                let secs = record.timestamp.timestamp() - 1_700_000_000;
+                (record.type_, record.id, secs)
+            })
+            .collect()
+    }
+
+    // Writes the records as JSON lines with an index, which starts a chunk at each given record.
+    fn write_indexed(path: &Path, records: &[Record], chunks: &[usize]) {
+        let mut content = Vec::new();
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut chunk: Option<Chunk> = None;
+        for (i, record) in records.iter().enumerate() {
+            if chunks.contains(&i) {
+                entries.extend(chunk.take().map(Chunk::entry));
+            }
+            let offset = content.len() as u64;
+            chunk
+                .get_or_insert_with(|| Chunk::new(offset, record))
+                .add(record, 0);
+            record.encode(Format::Json, &mut content);
+        }
+        entries.extend(chunk.map(Chunk::entry));
+        fs::write(path, content).unwrap();
+        index::write(path, &entries).unwrap();
+    }
+
+    fn samples() -> Vec<Record> {
+        let mut with_peer = record(1, Type_::Open, 1, b"");
+        with_peer.peer = Some(Peer {
+            pid: Some(42),
+            uid: 1000,
+            gid: 1000,
+            exe: Some("/usr/bin/perl".into()),
+            cmdline: Some("perl process_perfdata.pl".into()),
+        });
+        let mut with_latency = record(3, Type_::Latency, 1, b"UPDATE");
+        with_latency.latency_us = Some(1234);
+        vec![
+            record(0, Type_::Meta, 0, br#"{"hostname":"a"}"#),
+            with_peer,
+            record(2, Type_::Send, 1, b"UPDATE /a.rrd 1:\xff\n\0"),
+            with_latency,
+            record(4, Type_::Close, 1, b"client closed"),
+        ]
+    }
+
+    #[test]
+    fn binary_roundtrip() {
+        let records = samples();
+        let mut buf = Vec::new();
+        for record in &records {
+            record.encode(Format::Binary, &mut buf);
+        }
+        let mut content = header();
+        content.extend(zstd::encode_all(&buf[..], 0).unwrap());
+        let path = temp("roundtrip.bin");
+        fs::write(&path, content).unwrap();
+        let read: Vec<Record> = read(&path).unwrap().map(Result::unwrap).collect();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(json(&read), json(&records));
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let records = samples();
+        for format in [Format::Json, Format::Flat] {
+            let mut buf = Vec::new();
+            for record in &records {
+                record.encode(format, &mut buf);
+            }
+            let lines = std::str::from_utf8(&buf).unwrap().lines();
+            assert!(lines.clone().all(|line| layout(line) == format));
+            let read: Vec<Record> = lines.map(|line| parse_line(line).unwrap()).collect();
+            assert_eq!(json(&read), json(&records));
+        }
+    }
+
+    #[test]
+    fn detects_format() {
+        let path = temp("detect");
+        let detected = |content: &[u8]| {
+            fs::write(&path, content).unwrap();
+            detect(&mut BufReader::new(fs::File::open(&path).unwrap()))
+        };
+        assert_eq!(detected(&header()).unwrap(), Format::Binary);
+        assert_eq!(detected(b"{\"timestamp\":").unwrap(), Format::Json);
+        assert_eq!(detected(b"").unwrap(), Format::Json);
+        assert!(detected(&[MAGIC, &[VERSION + 1]].concat()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cut_off_binary_ends_the_recording() {
+        let mut buf = Vec::new();
+        for record in samples() {
+            record.encode(Format::Binary, &mut buf);
+        }
+        let mut encoder = zstd::Encoder::new(header(), 0).unwrap();
+        encoder.write_all(&buf).unwrap();
+        let mut content = encoder.finish().unwrap();
+        content.truncate(content.len() - 8);
+        let path = temp("cut.bin");
+        fs::write(&path, content).unwrap();
+        let read: Vec<io::Result<Record>> = read(&path).unwrap().collect();
+        fs::remove_file(&path).unwrap();
+        assert!(read.last().unwrap().is_err());
+        assert!(read[..read.len() - 1].iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn window_reads_on_within_grace() {
+        let records = [
+            record(0, Type_::Meta, 0, b"{}"),
+            record(10, Type_::Send, 1, b"a\n"),
+            record(70, Type_::Send, 1, b"b\n"),
+            record(50, Type_::Send, 1, b"c\n"),
+            record(200, Type_::Send, 1, b"d\n"),
+            record(55, Type_::Send, 1, b"e\n"),
+        ];
+        let path = temp("grace.jsonl");
+        let mut content = Vec::new();
+        for record in &records {
+            record.encode(Format::Json, &mut content);
+        }
+        fs::write(&path, content).unwrap();
+        let window = Window {
+            from: None,
+            to: Some(records[0].timestamp + TimeDelta::seconds(60)),
+        };
+        let read = seconds(super::records(&path, window));
+        fs::remove_file(&path).unwrap();
+        let send = Type_::Send;
+        assert_eq!(read, [(Type_::Meta, 0, 0), (send, 1, 10), (send, 1, 50)]);
+    }
+
+    #[test]
+    fn window_keeps_opens_of_active_connections() {
+        use Type_::{Close, Meta, Open, Send};
+        let records = [
+            record(0, Meta, 0, b"{}"),
+            record(1, Open, 1, b""),
+            record(3, Open, 2, b""),
+            // second chunk
+            record(10, Close, 2, b""),
+            record(12, Open, 3, b""),
+            // third chunk, with a record written late
+            record(20, Send, 1, b"a\n"),
+            record(22, Open, 5, b""),
+            record(23, Open, 6, b""),
+            record(25, Close, 5, b""),
+            record(33, Send, 3, b"b\n"),
+            // fourth chunk, which the window starts in
+            record(30, Send, 1, b"c\n"),
+            record(31, Open, 4, b""),
+            record(40, Close, 1, b""),
+        ];
+        let path = temp("opens.jsonl");
+        write_indexed(&path, &records, &[3, 5, 10]);
+        let window = Window {
+            from: Some(records[0].timestamp + TimeDelta::milliseconds(30_500)),
+            to: None,
+        };
+        let read = seconds(super::records(&path, window));
+        fs::remove_file(index::path(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+        let expected = [
+            (Meta, 0, 0),
+            (Open, 1, 1),
+            (Open, 3, 12),
+            (Open, 6, 23),
+            (Send, 3, 33),
+            (Open, 4, 31),
+            (Close, 1, 40),
+        ];
+        assert_eq!(read, expected);
+    }
+}
@@ -0,0 +1,338 @@
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Seek, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+use crate::event::Type_;
+use crate::format::{self, Format, Record};
+
+// The index splits a part of a recording into chunks of about this many bytes of records.
+// Binary chunks are zstd frames of their own, so that decoding can start at any of them.
+pub const CHUNK_SIZE: u64 = 1 << 20;
+
+// Where a chunk starts, when its first record was taken and which connections were opened and
+// closed in it.
+#[derive(Deserialize, Serialize)]
+pub struct Entry {
+    pub offset: u64,
+    pub timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub opened: Vec<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub closed: Vec<u64>,
+}
+
+// The chunk, which is being written or read.
+pub struct Chunk {
+    entry: Entry,
+    size: u64,
+}
+
+impl Chunk {
+    pub fn new(offset: u64, record: &Record) -> Self {
+        let entry = Entry {
+            offset,
+            // As written, both formats keep µs.
+            timestamp: record.timestamp.trunc_subsecs(6),
+            opened: Vec::new(),
+            closed: Vec::new(),
+        };
+        Self { entry, size: 0 }
+    }
+
+    // Adds a record of `size` bytes and tells whether the chunk is full.
+    pub fn add(&mut self, record: &Record, size: u64) -> bool {
+        match record.type_ {
+            Type_::Open => self.entry.opened.push(record.id),
+            Type_::Close => self.entry.closed.push(record.id),
+            _ => (),
+        }
+        self.size += size;
+        self.size >= CHUNK_SIZE
+    }
+
+    pub fn entry(self) -> Entry {
+        self.entry
+    }
+}
+
+// The index of `datalog.jsonl` is `datalog.jsonl.idx`, with an entry per line.
+pub fn path(part: &Path) -> PathBuf {
+    let mut path = part.as_os_str().to_owned();
+    path.push(".idx");
+    path.into()
+}
+
+pub fn is_index(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "idx")
+}
+
+pub fn line(entry: &Entry) -> String {
+    to_string(entry).unwrap() + "\n"
+}
+
+pub fn load(part: &Path) -> io::Result<Vec<Entry>> {
+    let file = BufReader::new(fs::File::open(path(part))?);
+    file.lines()
+        .map(|line| from_str(&line?).map_err(io::Error::other))
+        .collect()
+}
+
+// Indexes a part by reading it, e.g., one written before spy kept an index. A binary part
+// written without an index is a single zstd frame and gets a single entry.
+pub fn build(part: &Path) -> io::Result<Vec<Entry>> {
+    let mut reader = BufReader::new(fs::File::open(part)?);
+    let mut entries = Vec::new();
+    let mut chunk: Option<Chunk> = None;
+    match format::detect(&mut reader)? {
+        Format::Binary => loop {
+            let offset = reader.stream_position()?;
+            if reader.fill_buf()?.is_empty() {
+                break;
+            }
+            let mut decoder = zstd::Decoder::with_buffer(reader)?.single_frame();
+            let mut frame: Option<Chunk> = None;
+            let complete = loop {
+                match format::read_binary(&mut decoder) {
+                    Ok(Some(record)) => {
+                        let frame = frame.get_or_insert_with(|| Chunk::new(offset, &record));
+                        frame.add(&record, 0);
+                    }
+                    Ok(None) => break true,
+                    Err(_) => break false,
+                }
+            };
+            // A frame cut off by a crash still gets its entry, but ends the part.
+            entries.extend(frame.map(Chunk::entry));
+            if !complete {
+                break;
+            }
+            reader = decoder.finish();
+        },
+        _ => loop {
+            let offset = reader.stream_position()?;
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            let Ok(record) = format::parse_line(&line) else {
+                break;
+            };
+            let current = chunk.get_or_insert_with(|| Chunk::new(offset, &record));
+            if current.add(&record, line.len() as u64) {
+                entries.extend(chunk.take().map(Chunk::entry));
+            }
+        },
+    }
+    entries.extend(chunk.map(Chunk::entry));
+    Ok(entries)
+}
+
+pub fn write(part: &Path, entries: &[Entry]) -> io::Result<()> {
+    let path = path(part);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = io::BufWriter::new(fs::File::create(&tmp)?);
+    for entry in entries {
+        file.write_all(line(entry).as_bytes())?;
+    }
+    file.into_inner().map_err(|e| e.into_error())?;
+    fs::rename(&tmp, path)
+}
+
+// Connections opened in the chunk at `offset`, whose `open` records are taken before `until`.
+pub struct Opened {
+    pub offset: u64,
+    pub until: DateTime<Utc>,
+    pub ids: Vec<u64>,
+}
+
+// Where to start reading a part to see every record taken since `from`, and the connections
+// opened before that and not closed yet. Indexes written before spy noted closed connections
+// make every connection opened before look active.
+#[derive(Default)]
+pub struct Start {
+    pub offset: u64,
+    pub active: Vec<Opened>,
+}
+
+// Records are written slightly out of order, so reading starts a chunk earlier than necessary.
+// Without a usable index, the part is read from the start.
+pub fn start(part: &Path, from: DateTime<Utc>) -> Start {
+    let entries = match load(part) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("no index for {}: {e}", part.display());
+            return Start::default();
+        }
+    };
+    let len = fs::metadata(part).map_or(0, |metadata| metadata.len());
+    if entries.iter().any(|entry| entry.offset >= len) {
+        warn!("ignoring index of {}, which doesn't match", part.display());
+        return Start::default();
+    }
+    let first = match entries.partition_point(|entry| entry.timestamp <= from) {
+        0 | 1 => return Start::default(),
+        n => n - 2,
+    };
+    let skipped = &entries[..first];
+    let closed: HashSet<u64> = skipped
+        .iter()
+        .flat_map(|entry| entry.closed.iter().copied())
+        .collect();
+    let active = skipped
+        .iter()
+        .zip(&entries[1..])
+        .map(|(entry, next)| Opened {
+            offset: entry.offset,
+            until: next.timestamp,
+            ids: (entry.opened.iter())
+                .filter(|id| !closed.contains(id))
+                .copied()
+                .collect(),
+        })
+        .filter(|opened| !opened.ids.is_empty())
+        .collect();
+    Start {
+        offset: entries[first].offset,
+        active,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    fn record(secs: i64, type_: Type_, id: u64, message: &[u8]) -> Record {
+        Record {
+            timestamp: time(secs),
+            type_,
+            id,
+            message: message.to_vec(),
+            latency_us: None,
+            peer: None,
+        }
+    }
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("spy-{}-{name}", std::process::id()))
+    }
+
+    fn entry(offset: u64, secs: i64, opened: &[u64], closed: &[u64]) -> Entry {
+        Entry {
+            offset,
+            timestamp: time(secs),
+            opened: opened.to_vec(),
+            closed: closed.to_vec(),
+        }
+    }
+
+    #[test]
+    fn binary_chunks_are_frames() {
+        let frames = [
+            vec![
+                record(0, Type_::Open, 1, b""),
+                record(1, Type_::Send, 1, b"a\n"),
+            ],
+            vec![
+                record(2, Type_::Close, 1, b""),
+                record(3, Type_::Open, 2, b""),
+            ],
+        ];
+        let mut content = format::header();
+        for frame in &frames {
+            let mut buf = Vec::new();
+            for record in frame {
+                record.encode(Format::Binary, &mut buf);
+            }
+            content.extend(zstd::encode_all(&buf[..], 0).unwrap());
+        }
+        let path = temp("frames.bin");
+        fs::write(&path, content).unwrap();
+        let entries = build(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].offset, format::header().len() as u64);
+        assert_eq!(
+            (&entries[0].opened[..], &entries[0].closed[..]),
+            (&[1][..], &[][..])
+        );
+        assert_eq!(
+            (&entries[1].opened[..], &entries[1].closed[..]),
+            (&[2][..], &[1][..])
+        );
+        assert_eq!(entries[1].timestamp, frames[1][0].timestamp);
+        let reader = format::read_at(&path, entries[1].offset).unwrap();
+        let ids: Vec<(Type_, u64)> = reader
+            .map(|r| r.map(|r| (r.type_, r.id)).unwrap())
+            .collect();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(ids, [(Type_::Close, 1), (Type_::Open, 2)]);
+    }
+
+    #[test]
+    fn json_chunks_start_at_lines() {
+        let large = vec![b'x'; CHUNK_SIZE as usize / 2];
+        let records = [
+            record(0, Type_::Send, 1, &large),
+            record(1, Type_::Send, 1, &large),
+            record(2, Type_::Send, 2, &large),
+        ];
+        let mut content = Vec::new();
+        for record in &records {
+            record.encode(Format::Json, &mut content);
+        }
+        let path = temp("lines.jsonl");
+        fs::write(&path, &content).unwrap();
+        let entries = build(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].offset, 0);
+        assert_eq!(content[entries[1].offset as usize - 1], b'\n');
+        let mut reader = format::read_at(&path, entries[1].offset).unwrap();
+        let next = reader.next().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((next.id, next.timestamp), (2, records[2].timestamp));
+    }
+
+    #[test]
+    fn start_backs_up_one_chunk() {
+        let path = temp("start.jsonl");
+        fs::write(&path, vec![b'\n'; 500]).unwrap();
+        let entries = [
+            entry(0, 0, &[1, 2], &[]),
+            entry(100, 10, &[3], &[2]),
+            entry(200, 20, &[4], &[]),
+            entry(300, 30, &[], &[3]),
+            entry(400, 40, &[], &[]),
+        ];
+        write(&path, &entries).unwrap();
+        let at = |secs| start(&path, time(secs));
+        assert_eq!(at(5).offset, 0);
+        assert_eq!(at(15).offset, 0);
+        assert_eq!(at(25).offset, 100);
+        let start = at(35);
+        assert_eq!(start.offset, 200);
+        let active: Vec<(u64, &[u64])> = (start.active.iter())
+            .map(|opened| (opened.offset, &opened.ids[..]))
+            .collect();
+        assert_eq!(active, [(0, &[1][..]), (100, &[3][..])]);
+        assert_eq!(start.active[0].until, entries[1].timestamp);
+        // Connection 3 is closed in the chunk read first, where the reader drops its `open`.
+        let start = at(45);
+        assert_eq!(start.offset, 300);
+        let ids: Vec<&[u64]> = start.active.iter().map(|opened| &opened.ids[..]).collect();
+        assert_eq!(ids, [&[1][..], &[3], &[4]]);
+        // An index, which points past the end of its part, is ignored.
+        fs::write(&path, vec![b'\n'; 400]).unwrap();
+        assert_eq!(at(45).offset, 0);
+        fs::remove_file(self::path(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,4 @@
+pub mod event;
+pub mod format;
+pub mod index;
+pub mod metrics;
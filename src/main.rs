@@ -1,21 +1,56 @@
-use base64::prelude::*;
-use clap::{ArgAction, Parser, Subcommand};
-use serde::Deserialize;
-use serde_json::{from_str, to_string};
+mod chaos;
+mod control;
+mod net;
+mod pcap;
+mod prometheus;
+mod protocol;
+mod recorder;
+mod recording;
+mod spool;
+mod systemd;
+mod tap;
+mod tee;
+
+use chaos::{Chaos, Faults};
+use chrono::{SecondsFormat, Utc};
+use clap::{ArgAction, Args, Parser, Subcommand};
+use control::Stats;
+use glob::Pattern;
+use net::{Address, Listener, Permissions, Stream};
+use nix::unistd::Group;
+use prototype::event::{DEFAULT_RRD_DIR, Metadata};
+use prototype::format::{self, Format, Record, Window};
+use prototype::index;
+use recorder::Recorder;
+use recording::{Output, Peer, Type_};
+use serde_json::to_string;
+use spool::{Spool, Spooler};
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::io::BufRead;
-use std::io::BufReader;
+use std::io;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tap::{Filter, Tap};
+use tee::{Sink, Tee};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{UnixListener, UnixStream};
 use tokio::signal::unix::{SignalKind, signal};
 use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{Instant, sleep};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{EnvFilter, fmt};
 
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const BUFFER_SIZE: usize = 64 * 1024;
+
 #[derive(Parser, Debug)]
 struct Arguments {
     #[clap(subcommand)]
@@ -24,70 +59,259 @@ struct Arguments {
     verbose: u8,
 }
 
+#[derive(Args, Debug, Clone)]
+struct RecordArgs {
+    #[clap(long, short)]
+    output: PathBuf,
+    #[clap(long, short)]
+    socket: Address,
+    #[clap(long, short)]
+    upstream: Option<Address>,
+    #[clap(long, value_parser = parse_group)]
+    socket_group: Option<u32>,
+    #[clap(long, value_parser = parse_mode)]
+    socket_mode: Option<u32>,
+    #[clap(long, short)]
+    pidfile: PathBuf,
+    #[clap(long)]
+    spool: Option<PathBuf>,
+    #[clap(long)]
+    alternate: Option<PathBuf>,
+    #[clap(long)]
+    swap: bool,
+    #[clap(long)]
+    paused: bool,
+    #[clap(long, value_parser = parse_size)]
+    rotate_size: Option<u64>,
+    #[clap(long, value_parser = parse_interval)]
+    rotate_interval: Option<Duration>,
+    #[clap(long, value_enum, default_value_t = Format::Json)]
+    format: Format,
+    #[clap(long)]
+    site: Option<String>,
+    #[clap(long)]
+    rrd_dir: Option<String>,
+    #[clap(long)]
+    label: Vec<String>,
+    #[clap(long, default_value_t = 4096)]
+    queue_size: usize,
+    #[clap(long)]
+    include_command: Vec<String>,
+    #[clap(long)]
+    exclude_command: Vec<String>,
+    #[clap(long, value_parser = Pattern::new)]
+    path_glob: Vec<Pattern>,
+    #[clap(long, value_parser = parse_interval)]
+    chaos_latency: Option<Duration>,
+    #[clap(long, value_parser = parse_percent)]
+    chaos_stall: Option<f64>,
+    #[clap(long, value_parser = parse_size)]
+    chaos_drop_after: Option<u64>,
+    #[clap(long, value_parser = parse_percent)]
+    chaos_error: Option<f64>,
+    #[clap(long, value_parser = Pattern::new)]
+    chaos_error_path: Vec<Pattern>,
+    #[clap(long)]
+    tee: Option<Sink>,
+    #[clap(long)]
+    metrics_listen: Option<SocketAddr>,
+    #[clap(long)]
+    metrics_textfile: Option<PathBuf>,
+}
+
 #[derive(Subcommand, Debug, Clone)]
 enum Command {
-    Record {
+    Record(Box<RecordArgs>),
+    Replay {
+        #[clap(long, short)]
+        input: PathBuf,
+        #[clap(long, short)]
+        socket: Address,
+        // Where the RRDs of the recorded site are found on this machine.
+        #[clap(long, default_value = "/tmp/rrd")]
+        rrd_dir: String,
+        #[clap(flatten)]
+        window: Window,
+    },
+    Decipher {
+        #[clap(long, short)]
+        input: PathBuf,
+        #[clap(long, value_parser = Pattern::new)]
+        program: Option<Pattern>,
+        #[clap(flatten)]
+        window: Window,
+    },
+    // Rewrites a recording in the given format, e.g., to compress an old JSON recording.
+    Convert {
+        #[clap(long, short)]
+        input: PathBuf,
         #[clap(long, short)]
         output: PathBuf,
+        #[clap(long, value_enum)]
+        format: Format,
+    },
+    // Writes a recording as pcapng with a TCP stream per connection, e.g., for Wireshark.
+    ExportPcap {
         #[clap(long, short)]
-        socket: PathBuf,
+        input: PathBuf,
         #[clap(long, short)]
-        pidfile: PathBuf,
+        output: PathBuf,
     },
-    Replay {
+    // Writes the time index of every part of a recording, e.g., one made by an older spy.
+    Index {
         #[clap(long, short)]
         input: PathBuf,
+    },
+    // Moves `<socket>.original` back, after spy was killed without cleaning up.
+    Restore {
         #[clap(long, short)]
         socket: PathBuf,
+        #[clap(long)]
+        alternate: Option<PathBuf>,
     },
-    Decipher {
+    // Uptime, connections and upstream of the spy running with the given pidfile.
+    Status {
         #[clap(long, short)]
-        input: PathBuf,
+        pidfile: PathBuf,
     },
+    // Bytes and commands per connection and per command.
+    Stats {
+        #[clap(long, short)]
+        pidfile: PathBuf,
+    },
+    // Deciphered live traffic.
+    Tail {
+        #[clap(long, short)]
+        pidfile: PathBuf,
+    },
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let factor = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(format!("unknown unit {unit}, expected K, M or G")),
+    };
+    let number: u64 = number.parse().map_err(|e| format!("{e}"))?;
+    match number.checked_mul(factor) {
+        Some(0) => Err("expected a size above 0".into()),
+        Some(size) => Ok(size),
+        None => Err(format!("{s} is too large")),
+    }
+}
+
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let factor = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return Err(format!("unknown unit {unit}, expected ms, s, m, h or d")),
+    };
+    let number: u64 = number.parse().map_err(|e| format!("{e}"))?;
+    match number.checked_mul(factor) {
+        Some(0) => Err("expected an interval above 0".into()),
+        Some(millis) => Ok(Duration::from_millis(millis)),
+        None => Err(format!("{s} is too long")),
+    }
+}
+
+fn parse_group(s: &str) -> Result<u32, String> {
+    if let Ok(gid) = s.parse() {
+        return Ok(gid);
+    }
+    match Group::from_name(s) {
+        Ok(Some(group)) => Ok(group.gid.as_raw()),
+        Ok(None) => Err(format!("unknown group {s}")),
+        Err(e) => Err(format!("could not look up group {s}: {e}")),
+    }
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    let mode = u32::from_str_radix(s, 8).map_err(|e| format!("{e}"))?;
+    match mode <= 0o7777 {
+        true => Ok(mode),
+        false => Err(format!("{s} is not an octal file mode")),
+    }
 }
 
+fn parse_percent(s: &str) -> Result<f64, String> {
+    let percent: f64 = s
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|e| format!("{e}"))?;
+    match (0.0..=100.0).contains(&percent) {
+        true => Ok(percent),
+        false => Err(format!("{percent} is not between 0 and 100")),
+    }
+}
+
+// Restores the socket on a clean shutdown. The lock is released only after the socket has been
+// moved back, so a lock, which can be taken next to a `.original`, was left by a dead spy.
+// With `swap`, the socket is hard linked to `to` instead of moved, so it never disappears:
+// spy's listener and later the original are renamed over `from`, which replaces it atomically.
 struct Rename {
     from: PathBuf,
     to: PathBuf,
+    swap: bool,
+    _lock: fs::File,
 }
 
 impl Rename {
-    fn new(from: PathBuf, to: PathBuf) -> Self {
-        fs::rename(&from, &to).unwrap();
-        Self { from, to }
+    fn new(from: PathBuf, to: PathBuf, swap: bool, lock: fs::File) -> Self {
+        if swap {
+            fs::hard_link(&from, &to).unwrap();
+        } else {
+            fs::rename(&from, &to).unwrap();
+        }
+        Self {
+            from,
+            to,
+            swap,
+            _lock: lock,
+        }
     }
 }
 
+// Replaces `to` by `from` in a single rename, going through a temporary link.
+fn swap_socket(from: &Path, to: &Path) -> io::Result<()> {
+    let tmp = with_suffix(to, ".spy.tmp");
+    let _ = fs::remove_file(&tmp);
+    fs::hard_link(from, &tmp)?;
+    fs::rename(&tmp, to)?;
+    fs::remove_file(from)
+}
+
 impl Drop for Rename {
     fn drop(&mut self) {
-        match fs::rename(&self.to, &self.from) {
+        let result = if self.swap {
+            swap_socket(&self.to, &self.from)
+        } else {
+            fs::rename(&self.to, &self.from)
+        };
+        match result {
             Ok(()) => info!("reset socket"),
             Err(e) => error!("could not reset socket: {e:?}"),
         }
     }
 }
 
-#[derive(Deserialize, serde::Serialize)]
-#[serde(rename_all = "lowercase")]
-enum Type_ {
-    Send,
-    Recv,
-}
-
-#[derive(Deserialize)]
-struct Fields {
-    type_: Type_,
-    id: u64,
-    message: String,
-}
-
-#[derive(Deserialize)]
-struct Log {
-    timestamp: String,
-    fields: Fields,
-}
-
-async fn setup_signal_handler(token: CancellationToken) -> JoinHandle<()> {
+async fn setup_signal_handler(
+    token: CancellationToken,
+    recording: Arc<AtomicBool>,
+    output: Output,
+) -> JoinHandle<()> {
     let mut sigint = signal(SignalKind::interrupt()).unwrap();
     let mut sigterm = signal(SignalKind::terminate()).unwrap();
     let mut sighup = signal(SignalKind::hangup()).unwrap();
@@ -95,171 +319,788 @@ async fn setup_signal_handler(token: CancellationToken) -> JoinHandle<()> {
     let mut sigusr2 = signal(SignalKind::user_defined2()).unwrap();
     let mut sigquit = signal(SignalKind::quit()).unwrap();
     let mut sigalrm = signal(SignalKind::alarm()).unwrap();
-    let mut sigpipe = signal(SignalKind::pipe()).unwrap();
     let handle = tokio::spawn(async move {
-        tokio::select! {
-            _ =  sigint.recv() => (),
-            _ =  sigterm.recv() => (),
-            _ =  sighup.recv() => (),
-            _ =  sigusr1.recv() => (),
-            _ =  sigusr2.recv() => (),
-            _ =  sigquit.recv() => (),
-            _ =  sigalrm.recv() => (),
-            _ =  sigpipe.recv() => (),
-        };
+        loop {
+            tokio::select! {
+                _ =  sigusr1.recv() => {
+                    recording.store(false, Ordering::Relaxed);
+                    info!("recording paused");
+                    continue;
+                },
+                _ =  sigusr2.recv() => {
+                    recording.store(true, Ordering::Relaxed);
+                    info!("recording resumed");
+                    continue;
+                },
+                _ =  sighup.recv() => {
+                    output.reopen();
+                    continue;
+                },
+                _ =  sigint.recv() => (),
+                _ =  sigterm.recv() => (),
+                _ =  sigquit.recv() => (),
+                _ =  sigalrm.recv() => (),
+            };
+            break;
+        }
         info!("signal received");
         token.cancel()
     });
     debug!("signal handler setup complete");
-    return handle;
+    handle
 }
 
-fn move_socket(socket: &Path) -> Rename {
-    info!("moving socket");
-    let original: PathBuf = {
-        let mut original_name = socket.as_os_str().to_owned();
-        original_name.push(".original");
-        original_name.into()
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    name.into()
+}
+
+fn lock_socket(socket: &Path) -> fs::File {
+    let path = with_suffix(socket, ".spy.lock");
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    match file.try_lock() {
+        Ok(()) => file,
+        Err(fs::TryLockError::WouldBlock) => {
+            panic!(
+                "user error, spy is already running for {}",
+                socket.display()
+            )
+        }
+        Err(fs::TryLockError::Error(e)) => panic!("could not lock {}: {e}", path.display()),
+    }
+}
+
+fn answers(path: &Path) -> bool {
+    std::os::unix::net::UnixStream::connect(path).is_ok()
+}
+
+// Repairs a `.original` left by a dead spy. If rrdcached still listens on it, it is moved back.
+// It is only removed, if it refuses connections and rrdcached, restarted in the meantime, answers
+// on `socket`. In any other case, e.g., rrdcached being stopped, both are left alone.
+fn restore_socket(socket: &Path, original: &Path) -> io::Result<bool> {
+    if fs::symlink_metadata(original).is_err() {
+        return Ok(false);
+    }
+    match std::os::unix::net::UnixStream::connect(original) {
+        Ok(_) => {
+            warn!("restoring {} left by a previous run", original.display());
+            fs::rename(original, socket).map_err(|e| {
+                io::Error::other(format!(
+                    "could not move {} back to {}: {e}",
+                    original.display(),
+                    socket.display()
+                ))
+            })?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused && answers(socket) => {
+            warn!(
+                "removing dead {} left by a previous run, rrdcached answers on {}",
+                original.display(),
+                socket.display()
+            );
+            fs::remove_file(original).map_err(|e| {
+                io::Error::other(format!("could not remove {}: {e}", original.display()))
+            })?;
+        }
+        Err(e) => {
+            return Err(io::Error::other(format!(
+                "{} is left by a previous run, but doesn't answer ({e}) and neither does {}; \
+                 start rrdcached or move it back by hand",
+                original.display(),
+                socket.display()
+            )));
+        }
+    }
+    Ok(true)
+}
+
+fn move_socket(socket: &Path, original: PathBuf, swap: bool) -> Rename {
+    let lock = lock_socket(socket);
+    if let Err(e) = restore_socket(socket, &original) {
+        panic!("user error, {e}");
+    }
+    info!("moving socket to {}", original.display());
+    Rename::new(socket.into(), original, swap, lock)
+}
+
+fn restore_main(stdout_filter: EnvFilter, socket: &Path, original: Option<PathBuf>) {
+    let stdout_layer = fmt::Layer::default().compact().with_filter(stdout_filter);
+    tracing_subscriber::registry().with(stdout_layer).init();
+    let original = original.unwrap_or_else(|| with_suffix(socket, ".original"));
+    let _lock = lock_socket(socket);
+    match restore_socket(socket, &original) {
+        Ok(true) => (),
+        Ok(false) => info!("nothing to restore for {}", socket.display()),
+        Err(e) => panic!("user error, {e}"),
+    }
+}
+
+// State shared by all connections of the proxy.
+struct Proxy {
+    to: Address,
+    spool: Option<Spool>,
+    recorder: Recorder,
+    filter: Option<Filter>,
+    chaos: Option<Chaos>,
+    tee: Option<Tee>,
+    stats: Stats,
+}
+
+// With `swap`, a unix listener is bound and set up at a temporary path first, and then renamed
+// over `from`, so that there is no moment in which `from` is missing. Otherwise, a socket left
+// behind at `from` by a dead spy is removed first.
+async fn listen(from: &Address, permissions: &Permissions, swap: bool) -> io::Result<Listener> {
+    let bind = match from {
+        Address::Unix(path) if swap => {
+            let tmp = with_suffix(path, ".spy.tmp");
+            let _ = fs::remove_file(&tmp);
+            Address::Unix(tmp)
+        }
+        Address::Unix(path) => {
+            net::remove_stale(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("could not remove {from}: {e}")))?;
+            from.clone()
+        }
+        from => from.clone(),
     };
-    Rename::new(socket.into(), original)
+    let listener = bind.bind().await?;
+    if let Address::Unix(path) = &bind {
+        permissions.apply(path).map_err(|e| {
+            io::Error::new(e.kind(), format!("could not apply {permissions:?}: {e}"))
+        })?;
+        debug!("applied {permissions:?} to {bind}");
+    }
+    if let (Address::Unix(tmp), Address::Unix(path)) = (&bind, from)
+        && tmp != path
+    {
+        fs::rename(tmp, path)?;
+        debug!("swapped {bind} over {from}");
+    }
+    info!("listening to {from}");
+    Ok(listener)
 }
 
-async fn serve(token: CancellationToken, from: &Path, to: &Path) {
+async fn serve(token: CancellationToken, listener: Listener, proxy: Arc<Proxy>) {
     let mut set = JoinSet::new();
     let mut count = 0u64;
-    let listener = UnixListener::bind(from).unwrap();
-    info!("listening to {}", from.as_os_str().to_string_lossy());
     loop {
-        let to_stream = tokio::select! {
-            to_stream = UnixStream::connect(to) => to_stream.unwrap(),
-            _ = token.cancelled() => break,
-        };
         let (from_stream, addr) = tokio::select! {
-            from_stream = listener.accept() => from_stream.unwrap(),
+            from_stream = listener.accept() => match from_stream {
+                Ok(from_stream) => from_stream,
+                Err(e) => {
+                    error!("could not accept connection: {e:?}");
+                    continue;
+                }
+            },
             _ = token.cancelled() => break,
         };
         count += 1;
         debug!(id = count, "accepted connection {:?}", addr);
-        set.spawn(forward_traffic(
-            count,
-            token.clone(),
-            from_stream,
-            to_stream,
-        ));
+        let (token, proxy) = (token.clone(), proxy.clone());
+        // Reading /proc for the client is left to the connection, so accepting isn't held up.
+        set.spawn(async move {
+            let peer = from_stream.peer();
+            if let Some(peer) = &peer {
+                debug!(id = count, "client {}", peer.program());
+            }
+            proxy
+                .stats
+                .open(count, peer.as_ref().map_or(&addr, |peer| peer.program()));
+            proxy.recorder.open(count, peer, &addr);
+            let reason = handle_connection(count, token, from_stream, proxy.clone()).await;
+            debug!(id = count, "connection closed, {reason}");
+            proxy.recorder.close(count, reason);
+            proxy.stats.close(count);
+        });
     }
     info!("awaiting connections");
     set.join_all().await;
     info!("all connections closed");
 }
 
-async fn forward_traffic(
+async fn connect_upstream(id: u64, token: &CancellationToken, proxy: &Proxy) -> Option<Stream> {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1.. {
+        match proxy.to.connect().await {
+            Ok(stream) => {
+                debug!(
+                    id = id,
+                    "connected to {} after {attempt} attempt(s)", proxy.to
+                );
+                connected(id, proxy);
+                return Some(stream);
+            }
+            Err(e) => connect_failed(id, proxy, attempt, &e),
+        }
+        tokio::select! {
+            _ = sleep(backoff) => (),
+            _ = token.cancelled() => break,
+        };
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    None
+}
+
+fn connected(id: u64, proxy: &Proxy) {
+    let message = format!("connected to {}", proxy.to);
+    proxy.recorder.text(Type_::Connect, id, message);
+    proxy.stats.upstream_up();
+}
+
+fn connect_failed(id: u64, proxy: &Proxy, attempt: u64, e: &io::Error) {
+    error!(
+        id = id,
+        "could not connect to {} (attempt {attempt}): {e:?}", proxy.to
+    );
+    let message = format!("attempt {attempt} failed: {e}");
+    proxy.recorder.text(Type_::Connect, id, message);
+    proxy.stats.upstream_down(e);
+}
+
+async fn drain_spool(id: u64, spool: &Spool, to_stream: &mut Stream) {
+    if let Err(e) = spool.drain(to_stream).await {
+        error!(id = id, "could not drain spool: {e:?}");
+    }
+}
+
+// Why connections end, as recorded in their `close` event.
+const CLIENT_CLOSED: &str = "client closed";
+const CLIENT_CLOSED_FIRST: &str = "client closed first";
+const UPSTREAM_CLOSED_FIRST: &str = "upstream closed first";
+const STOPPED: &str = "spy stopped";
+const DROPPED: &str = "dropped by chaos";
+const FAILED: &str = "failed";
+
+async fn handle_connection(
     id: u64,
     token: CancellationToken,
-    mut from_stream: UnixStream,
-    mut to_stream: UnixStream,
-) {
+    mut from_stream: Stream,
+    proxy: Arc<Proxy>,
+) -> &'static str {
+    let to_stream = match &proxy.spool {
+        None => connect_upstream(id, &token, &proxy).await,
+        Some(spool) => match proxy.to.connect().await {
+            Ok(mut to_stream) => {
+                connected(id, &proxy);
+                drain_spool(id, spool, &mut to_stream).await;
+                Some(to_stream)
+            }
+            Err(e) => {
+                connect_failed(id, &proxy, 1, &e);
+                return spool_traffic(id, token, from_stream, &proxy, spool).await;
+            }
+        },
+    };
+    match to_stream {
+        Some(to_stream) => {
+            let tap = Tap::new(id, &proxy);
+            forward_traffic(id, token, from_stream, to_stream, &proxy, tap, &[]).await
+        }
+        None => {
+            debug!(id = id, "closing connection without upstream");
+            from_stream
+                .shutdown()
+                .await
+                .map_err(|e| error!(id = id, "from {e:?}"))
+                .ok();
+            STOPPED
+        }
+    }
+}
+
+// Answers the client in place of the upstream, until the upstream can be reached again.
+async fn spool_traffic(
+    id: u64,
+    token: CancellationToken,
+    mut from_stream: Stream,
+    proxy: &Proxy,
+    spool: &Spool,
+) -> &'static str {
+    info!(id = id, "upstream unavailable, spooling updates");
+    let reason = match spool_relay(id, &token, &mut from_stream, proxy, spool).await {
+        Ok(Some((to_stream, tap, pending))) => {
+            return forward_traffic(id, token, from_stream, to_stream, proxy, tap, &pending).await;
+        }
+        Ok(None) if token.is_cancelled() => STOPPED,
+        Ok(None) => CLIENT_CLOSED,
+        Err(e) => {
+            connection_failed(id, proxy, &e);
+            FAILED
+        }
+    };
+    debug!(id = id, "closing spooled connection");
+    from_stream
+        .shutdown()
+        .await
+        .map_err(|e| error!(id = id, "from {e:?}"))
+        .ok();
+    reason
+}
+
+// Once the upstream is back, the connection is handed over to `relay` with its tap, so that the
+// session goes on where it is, and with the partial command the client has sent so far.
+async fn spool_relay<'a>(
+    id: u64,
+    token: &CancellationToken,
+    from_stream: &mut Stream,
+    proxy: &'a Proxy,
+    spool: &Spool,
+) -> io::Result<Option<(Stream, Tap<'a>, Vec<u8>)>> {
+    let mut spooler = Spooler::default();
+    let mut tap = Tap::new(id, proxy);
+    let mut attempt = 1;
+    let mut backoff = INITIAL_BACKOFF;
+    let reconnect = sleep(backoff);
+    tokio::pin!(reconnect);
+    let mut from_buf = vec![0u8; BUFFER_SIZE];
     while !token.is_cancelled() {
-        let mut from_buf = [0u8; 1024];
-        let mut to_buf = [0u8; 1024];
         tokio::select! {
             from_read = from_stream.read(&mut from_buf) => {
-                let n = from_read.unwrap();
-                    let message: &str = &BASE64_STANDARD.encode(&from_buf[..n]);
-                    trace!(type_="send", id=id, message=message);
+                let n = from_read.map_err(context("client read"))?;
+                tap.send(&from_buf[..n]);
+                if n == 0 {
+                    break;
+                }
+                let reply = spooler.feed(&from_buf[..n]);
+                spool.append(&reply.updates).map_err(context("spool"))?;
+                tap.answered(&reply.response);
+                from_stream.write_all(&reply.response).await.map_err(context("client write"))?;
+                if reply.quit {
+                    break;
+                }
+            },
+            _ = &mut reconnect => {
+                attempt += 1;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                reconnect.as_mut().reset(Instant::now() + backoff);
+                if spooler.in_batch() {
+                    continue;
+                }
+                match proxy.to.connect().await {
+                    Ok(mut to_stream) => {
+                        info!(id = id, "upstream available again after {attempt} attempt(s)");
+                        connected(id, proxy);
+                        drain_spool(id, spool, &mut to_stream).await;
+                        return Ok(Some((to_stream, tap, spooler.pending)));
+                    }
+                    Err(e) => connect_failed(id, proxy, attempt, &e),
+                }
+            },
+            // Another connection found the upstream and drained the spool. Spooling on would
+            // leave these updates behind the ones it forwards.
+            _ = spool.drained() => {
+                debug!(id = id, "spool drained, reconnecting");
+                backoff = INITIAL_BACKOFF;
+                reconnect.as_mut().reset(Instant::now());
+            },
+            _ = token.cancelled() => break,
+        };
+    }
+    Ok(None)
+}
+
+async fn forward_traffic(
+    id: u64,
+    token: CancellationToken,
+    mut from_stream: Stream,
+    mut to_stream: Stream,
+    proxy: &Proxy,
+    tap: Tap<'_>,
+    pending: &[u8],
+) -> &'static str {
+    let relayed = relay(
+        id,
+        &token,
+        &mut from_stream,
+        &mut to_stream,
+        proxy,
+        tap,
+        pending,
+    );
+    let reason = match relayed.await {
+        Ok(reason) => reason,
+        Err(e) => {
+            connection_failed(id, proxy, &e);
+            FAILED
+        }
+    };
+    debug!(id = id, "closing connection");
+    let (shutdown_from, shutdown_to) = tokio::join!(from_stream.shutdown(), to_stream.shutdown());
+    shutdown_to.map_err(|e| debug!(id = id, "to {e:?}")).ok();
+    shutdown_from
+        .map_err(|e| debug!(id = id, "from {e:?}"))
+        .ok();
+    reason
+}
+
+// Relays both directions until both are closed. The EOF of one side is passed on to the other
+// side as a half-close, so that, e.g., the rest of a response still reaches a client, which
+// shut down its writing side after the request. `pending` is the start of a command, which the
+// client sent before, and which the tap has already seen.
+async fn relay(
+    id: u64,
+    token: &CancellationToken,
+    from_stream: &mut Stream,
+    to_stream: &mut Stream,
+    proxy: &Proxy,
+    mut tap: Tap<'_>,
+    pending: &[u8],
+) -> io::Result<&'static str> {
+    let mut from_buf = vec![0u8; BUFFER_SIZE];
+    let mut to_buf = vec![0u8; BUFFER_SIZE];
+    let mut faults = Faults::new(id, proxy);
+    // Faults pass on complete lines only, so the start is sent along with the rest of the line.
+    match &mut faults {
+        Some(faults) => {
+            faults.send(pending);
+        }
+        None => to_stream
+            .write_all(pending)
+            .await
+            .map_err(context("upstream write"))?,
+    }
+    let mut client_open = true;
+    let mut upstream_open = true;
+    let mut first = None;
+    while !token.is_cancelled() {
+        tokio::select! {
+            from_read = from_stream.read(&mut from_buf), if client_open => {
+                let n = from_read.map_err(context("client read"))?;
+                    tap.send(&from_buf[..n]);
+                    // Without faults, the chunk is passed on as it is read.
+                    let (upstream, injected): (Cow<[u8]>, _) = match &mut faults {
+                        Some(faults) => {
+                            let (upstream, injected) = faults.send(&from_buf[..n]);
+                            (upstream.into(), injected)
+                        }
+                        None => (from_buf[..n].into(), Vec::new()),
+                    };
                     tokio::select! {
-                        write = to_stream.write_all(&from_buf[..n]) => write.unwrap(),
-                        _ = token.cancelled() => break,
+                        write = to_stream.write_all(&upstream) => write.map_err(context("upstream write"))?,
+                        _ = token.cancelled() => return Ok(STOPPED),
                     };
+                    if !injected.is_empty() {
+                        tap.recv(&injected);
+                        from_stream.write_all(&injected).await.map_err(context("client write"))?;
+                    }
                     if n == 0 {
-                        break;
+                        debug!(id = id, "client closed, closing upstream side");
+                        to_stream.shutdown().await.map_err(context("upstream shutdown"))?;
+                        client_open = false;
+                        first.get_or_insert(CLIENT_CLOSED_FIRST);
                     };
                 },
-            to_read = to_stream.read(&mut to_buf) => {
-                let n = to_read.unwrap();
-                    let message: &str = &BASE64_STANDARD.encode(&to_buf[..n]);
-                    trace!(type_="recv", id=id, message=message);
+            to_read = to_stream.read(&mut to_buf), if upstream_open => {
+                let n = to_read.map_err(context("upstream read"))?;
+                    let client: Cow<[u8]> = match &mut faults {
+                        Some(faults) => faults.recv(&to_buf[..n]).await.into(),
+                        None => to_buf[..n].into(),
+                    };
+                    if !client.is_empty() || n == 0 {
+                        tap.recv(&client);
+                    }
                     tokio::select! {
-                        write = from_stream.write_all(&to_buf[..n]) => write.unwrap(),
-                        _ = token.cancelled() => break,
+                        write = from_stream.write_all(&client) => write.map_err(context("client write"))?,
+                        _ = token.cancelled() => return Ok(STOPPED),
                     }
                     if n == 0 {
-                        break;
+                        debug!(id = id, "upstream closed, closing client side");
+                        from_stream.shutdown().await.map_err(context("client shutdown"))?;
+                        upstream_open = false;
+                        first.get_or_insert(UPSTREAM_CLOSED_FIRST);
                     };
                 },
-            _ = token.cancelled() => break,
+            _ = token.cancelled() => return Ok(STOPPED),
         };
+        if faults.as_ref().is_some_and(|faults| faults.dropped) {
+            return Ok(DROPPED);
+        }
+        if let Some(first) = first
+            && !client_open
+            && !upstream_open
+        {
+            return Ok(first);
+        }
+    }
+    Ok(STOPPED)
+}
+
+fn context(side: &'static str) -> impl FnOnce(io::Error) -> io::Error {
+    move |e| io::Error::new(e.kind(), format!("{side}: {e}"))
+}
+
+fn connection_failed(id: u64, proxy: &Proxy, e: &io::Error) {
+    error!(id = id, "connection failed, {e}");
+    proxy.recorder.text(Type_::Error, id, e.to_string());
+}
+
+// Describes the capture for the metadata record. Within an OMD site, the site and its RRD
+// directory are taken from the environment.
+fn metadata(args: &RecordArgs) -> Metadata {
+    let hostname = nix::unistd::gethostname().map(|name| name.to_string_lossy().into_owned());
+    let rrd_dir = std::env::var("OMD_ROOT").map(|root| format!("{root}/var/check_mk/rrd"));
+    Metadata {
+        hostname: hostname.unwrap_or_else(|_| "unknown".into()),
+        site: args.site.clone().or(std::env::var("OMD_SITE").ok()),
+        socket: args.socket.to_string(),
+        rrd_dir: args.rrd_dir.clone().or(rrd_dir.ok()),
+        started: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        version: env!("CARGO_PKG_VERSION").into(),
+        labels: args.label.clone(),
     }
-    debug!(id = id, "closing connection");
-    let (shutdown_from, shutdown_to) = tokio::join!(from_stream.shutdown(), to_stream.shutdown());
-    shutdown_to.map_err(|e| error!(id = id, "to {e:?}")).ok();
-    shutdown_from
-        .map_err(|e| error!(id = id, "from {e:?}"))
-        .ok();
 }
 
 #[tokio::main]
-async fn record_main(stdout_filter: EnvFilter, output: &Path, socket: &Path, pidfile: &Path) {
+async fn record_main(stdout_filter: EnvFilter, args: RecordArgs) {
     let stdout_layer = fmt::Layer::default().compact().with_filter(stdout_filter);
+    tracing_subscriber::registry().with(stdout_layer).init();
     let pid = process::id().to_string();
-    fs::write(pidfile, pid).unwrap();
-    let file = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(output)
-        .unwrap();
-
-    let json_layer = fmt::Layer::default()
-        .json()
-        .with_writer(file)
-        .with_level(false)
-        .with_target(false)
-        .with_filter(EnvFilter::new("spy[{type_}]"));
-    tracing_subscriber::registry()
-        .with(stdout_layer)
-        .with(json_layer)
-        .init();
+    fs::write(&args.pidfile, pid).unwrap();
+    let metadata = metadata(&args);
+    info!("{metadata}");
+    let output = Output::open(
+        &args.output,
+        args.rotate_size,
+        args.rotate_interval,
+        args.format,
+        Some(Record::meta(&metadata)),
+    )
+    .unwrap();
+    let recording = Arc::new(AtomicBool::new(!args.paused));
+    let (recorder, recorder_handle) =
+        Recorder::spawn(output.clone(), args.queue_size, recording.clone());
+    let spool = args.spool.map(|path| Spool::open(&path).unwrap());
     let token = CancellationToken::new();
-    let handle = setup_signal_handler(token.clone()).await;
-    let rename = move_socket(socket);
-    let _ = tokio::join!(handle, serve(token, &rename.from, &rename.to));
-    drop(rename)
+    let handle = setup_signal_handler(token.clone(), recording, output).await;
+    let activated = systemd::listener();
+    let (rename, from, to, mut permissions) = match (args.socket, args.upstream) {
+        (_, None) if activated.is_some() => {
+            panic!("user error, --upstream is required with socket activation")
+        }
+        (Address::Unix(socket), None) => {
+            let original = args
+                .alternate
+                .unwrap_or_else(|| with_suffix(&socket, ".original"));
+            let rename = move_socket(&socket, original, args.swap);
+            let permissions = Permissions::of(&rename.to).unwrap();
+            let from = Address::Unix(rename.from.clone());
+            let to = Address::Unix(rename.to.clone());
+            (Some(rename), from, to, permissions)
+        }
+        (from, Some(to)) => (None, from, to, Permissions::default()),
+        (Address::Tcp(_), None) => panic!("user error, --upstream is required for tcp sockets"),
+    };
+    // Sockets passed by systemd get theirs from the socket unit, i.e., `SocketMode=`.
+    if args.socket_group.is_some() || args.socket_mode.is_some() {
+        match (&from, &activated) {
+            (_, Some(_)) => panic!(
+                "user error, --socket-group and --socket-mode don't apply to a socket passed by \
+                 systemd, set SocketGroup= and SocketMode= in the socket unit instead"
+            ),
+            (Address::Tcp(_), None) => {
+                panic!("user error, --socket-group and --socket-mode don't apply to tcp sockets")
+            }
+            (Address::Unix(_), None) => (),
+        }
+    }
+    if let Some(gid) = args.socket_group {
+        permissions.gid = Some(gid);
+    }
+    if let Some(mode) = args.socket_mode {
+        permissions.mode = Some(mode);
+    }
+    let status = format!("forwarding {from} to {to}");
+    info!("{status}");
+    let filter = Filter::new(
+        &args.include_command,
+        &args.exclude_command,
+        &args.path_glob,
+    );
+    let chaos = Chaos::new(
+        args.chaos_latency,
+        args.chaos_stall,
+        args.chaos_drop_after,
+        args.chaos_error,
+        &args.chaos_error_path,
+    );
+    if chaos.is_some() {
+        warn!("injecting faults into forwarded connections");
+    }
+    let (tee, tee_handle) = match args.tee {
+        Some(sink) => {
+            info!("teeing updates to {sink}");
+            let (tee, handle) = Tee::spawn(sink, args.queue_size);
+            (Some(tee), Some(handle))
+        }
+        None => (None, None),
+    };
+    let proxy = Arc::new(Proxy {
+        to,
+        spool,
+        recorder,
+        filter,
+        chaos,
+        tee,
+        stats: Stats::new(status.clone()),
+    });
+    // A socket bound by spy itself is removed again on stop. A moved socket is reset instead,
+    // and one passed by systemd belongs to systemd.
+    let (listener, bound) = match activated {
+        Some(listener) => {
+            info!("listening to socket passed by systemd");
+            (listener.unwrap(), None)
+        }
+        None => match listen(&from, &permissions, args.swap).await {
+            Ok(listener) => match (&from, &rename) {
+                (Address::Unix(path), None) => (listener, Some(path.clone())),
+                _ => (listener, None),
+            },
+            Err(e) => panic!("user error, could not listen to {from}: {e}"),
+        },
+    };
+    // systemd is told that spy is ready once the upstream is reachable as well. Connections are
+    // forwarded in the meantime.
+    let ready = async {
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1.. {
+            let connect = tokio::select! {
+                connect = proxy.to.connect() => connect,
+                _ = token.cancelled() => return,
+            };
+            match connect {
+                Ok(_) => {
+                    systemd::notify(&format!("READY=1\nSTATUS={status}"));
+                    return;
+                }
+                Err(e) => {
+                    warn!("{} isn't reachable yet (attempt {attempt}): {e}", proxy.to);
+                    systemd::notify(&format!("STATUS=waiting for {}: {e}", proxy.to));
+                }
+            }
+            tokio::select! {
+                _ = sleep(backoff) => (),
+                _ = token.cancelled() => return,
+            };
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    };
+    // The socket is reset as soon as spy stops, so that new clients reach the original while
+    // spy is still waiting for its connections.
+    let reset = async {
+        token.cancelled().await;
+        systemd::notify("STOPPING=1");
+        drop(rename);
+        if let Some(path) = bound {
+            match fs::remove_file(&path) {
+                Ok(()) => info!("removed socket {}", path.display()),
+                Err(e) => error!("could not remove socket {}: {e:?}", path.display()),
+            }
+        }
+    };
+    let _ = tokio::join!(
+        handle,
+        ready,
+        reset,
+        serve(token.clone(), listener, proxy.clone()),
+        control::serve(token.clone(), control::path(&args.pidfile), proxy.clone()),
+        async {
+            if let Some(addr) = args.metrics_listen {
+                prometheus::serve(token.clone(), addr, proxy.clone()).await;
+            }
+        },
+        async {
+            if let Some(path) = args.metrics_textfile {
+                prometheus::write_textfile(token.clone(), path, proxy.clone()).await;
+            }
+        },
+        systemd::watchdog(token.clone()),
+    );
+    let dropped = proxy.recorder.dropped();
+    if dropped > 0 {
+        warn!("recorder dropped {dropped} record(s)");
+    }
+    if let Some(dropped) = proxy.tee.as_ref().map(Tee::dropped)
+        && dropped > 0
+    {
+        warn!("tee dropped {dropped} update(s)");
+    }
+    drop(proxy);
+    if let Some(tee_handle) = tee_handle {
+        tee_handle.await.unwrap();
+    }
+    recorder_handle.join().unwrap();
 }
 
-fn xchange_timestamp_update(message: &str) -> String {
+fn xchange_timestamp_update(message: &str, from: Option<&str>, to: &str) -> String {
     let mut result = message.to_string();
     if result.starts_with("UPDATE") {
-        result = result.replace("/opt/omd/sites/prod/var/check_mk/rrd", "/tmp/rrd");
+        result = Metadata::rewrite(from, &result, to);
         result = result.replace("rrd 174", "rrd 205");
     }
     result
 }
 
+// Replays every recorded connection over a connection of its own. It is opened at `open`, or
+// at the first `send` in older recordings, and closed at the EOF of the client or at `close`.
+// Paths below the RRD directory named by the metadata are moved below `rrd_dir`.
 #[tokio::main]
-async fn replay_main(stdout_filter: EnvFilter, input: &Path, socket: &Path) {
+async fn replay_main(
+    stdout_filter: EnvFilter,
+    input: &Path,
+    socket: &Address,
+    rrd_dir: &str,
+    window: Window,
+) {
     let stdout_layer = fmt::Layer::default().compact().with_filter(stdout_filter);
     tracing_subscriber::registry().with(stdout_layer).init();
-    let file = fs::File::open(input).unwrap();
-    let mut stream = UnixStream::connect(socket).await.unwrap();
-    info!("connected to {}", &socket.to_string_lossy());
+    let mut streams: HashMap<u64, (Stream, String)> = HashMap::new();
+    let mut recorded_rrd_dir = Some(DEFAULT_RRD_DIR.to_string());
     let mut buf = [0u8; 65536];
-    let mut commands = String::new();
-    for (line, line_count) in BufReader::new(file).lines().zip(0..) {
-        let log: Log = from_str(&line.unwrap()).unwrap();
+    for (record, line_count) in format::recording(input, window).zip(0..) {
+        let id = record.id;
         debug!(
             "{line_count}, {}, {}",
-            &log.timestamp,
-            &to_string(&log.fields.type_).unwrap()
+            &record.timestamp,
+            &to_string(&record.type_).unwrap()
         );
-        match log.fields.type_ {
+        match record.type_ {
+            Type_::Meta => {
+                if let Some(metadata) = record.metadata() {
+                    info!("{metadata}");
+                    recorded_rrd_dir = metadata.rrd_dir;
+                }
+            }
+            Type_::Open => {
+                streams.insert(id, (socket.connect().await.unwrap(), String::new()));
+                debug!(id = id, "connected to {socket}");
+            }
             Type_::Send => {
-                let bytes = BASE64_STANDARD.decode(&log.fields.message).unwrap();
-                commands += from_ascii(&bytes).unwrap();
+                let bytes = record.message;
+                if bytes.is_empty() {
+                    close_replayed(&mut streams, id).await;
+                    continue;
+                }
+                let (stream, commands) = match streams.entry(id) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        debug!(id = id, "connected to {socket}");
+                        entry.insert((socket.connect().await.unwrap(), String::new()))
+                    }
+                };
+                *commands += from_ascii(&bytes).unwrap();
                 let commands_clone = commands.clone();
                 let rrd_commands: Vec<&str> = commands_clone.split('\n').collect();
-                commands = rrd_commands.last().unwrap().to_string();
+                *commands = rrd_commands.last().unwrap().to_string();
 
                 for &rrd_command in rrd_commands.iter() {
-                    let mut modified = xchange_timestamp_update(rrd_command);
+                    let from = recorded_rrd_dir.as_deref();
+                    let mut modified = xchange_timestamp_update(rrd_command, from, rrd_dir);
                     if modified.is_empty() {
                         continue;
                     }
@@ -269,12 +1110,24 @@ async fn replay_main(stdout_filter: EnvFilter, input: &Path, socket: &Path) {
                     let _ = stream.read(&mut buf).await;
                 }
             }
-            Type_::Recv => {}
+            Type_::Close => close_replayed(&mut streams, id).await,
+            Type_::Recv | Type_::Connect | Type_::Error | Type_::Latency | Type_::Fault => {}
         };
-        if line_count % 100000 == 0{
+        if line_count % 100000 == 0 {
             info!("processed {line_count}");
         }
     }
+    info!("replayed, closing {} connection(s)", streams.len());
+    for id in streams.keys().copied().collect::<Vec<_>>() {
+        close_replayed(&mut streams, id).await;
+    }
+}
+
+async fn close_replayed(streams: &mut HashMap<u64, (Stream, String)>, id: u64) {
+    if let Some((mut stream, _)) = streams.remove(&id) {
+        debug!(id = id, "closing replayed connection");
+        stream.shutdown().await.ok();
+    }
 }
 
 fn from_ascii(message: &[u8]) -> Option<&str> {
@@ -284,34 +1137,193 @@ fn from_ascii(message: &[u8]) -> Option<&str> {
     None
 }
 
+#[derive(Default)]
+struct ClientStats {
+    connections: u64,
+    commands: u64,
+    updates: u64,
+}
+
 #[tokio::main]
-async fn decipher_main(stdout_filter: EnvFilter, input: &Path) {
+async fn decipher_main(
+    stdout_filter: EnvFilter,
+    input: &Path,
+    program: Option<&Pattern>,
+    window: Window,
+) {
     let stdout_layer = fmt::Layer::default().compact().with_filter(stdout_filter);
     tracing_subscriber::registry().with(stdout_layer).init();
-    let file = fs::File::open(input).unwrap();
     let mut update_count = 0;
-    for (line, _line_count) in BufReader::new(file).lines().zip(0..) {
-        let log: Log = from_str(&line.unwrap()).unwrap();
-        let bytes = BASE64_STANDARD.decode(&log.fields.message).unwrap();
-        let prompt = match log.fields.type_ {
-            Type_::Send => ">>",
-            Type_::Recv => "<<",
-        };
-        println!("{prompt} connection {}", log.fields.id);
-        for message in bytes.split(|&b| b == b'\n') {
-            match from_ascii(message) {
-                Some("") => continue,
-                Some(m) => {
-                    println!("{m}");
-                    if m.starts_with("UPDATE") {
-                        update_count += 1
-                    }
-                }
-                None => println!("non-ascii message of length {}", message.len()),
-            };
+    let mut latencies: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+    let mut peers: HashMap<u64, Peer> = HashMap::new();
+    let mut clients: BTreeMap<String, ClientStats> = BTreeMap::new();
+    for record in format::recording(input, window) {
+        if let Some(peer) = record.peer.clone() {
+            peers.insert(record.id, peer);
+        }
+        let peer = peers.get(&record.id);
+        let client = peer.map_or("unknown", Peer::program).to_string();
+        // Only connections of other clients are left out. The metadata and connections, whose
+        // client isn't known, e.g., in older recordings, are kept.
+        if let (Some(program), Some(peer)) = (program, peer) {
+            let exe = peer.exe.as_deref();
+            let cmdline = peer.cmdline.as_deref();
+            if !program.matches(&client)
+                && !exe.is_some_and(|exe| program.matches(exe))
+                && !cmdline.is_some_and(|cmdline| program.matches(cmdline))
+            {
+                continue;
+            }
+        }
+        if record.peer.is_some() {
+            clients.entry(client.clone()).or_default().connections += 1;
+        }
+        if let Type_::Latency = record.type_ {
+            if let Some(latency) = record.latency_us {
+                latencies
+                    .entry(record.text().into_owned())
+                    .or_default()
+                    .push(latency);
+            }
+            continue;
+        }
+        print!("{}", decipher(&record));
+        if !record.type_.is_traffic() {
+            continue;
+        }
+        for m in messages(&record.message).flatten() {
+            let stats = clients.entry(client.clone()).or_default();
+            if let Type_::Send = record.type_ {
+                stats.commands += 1;
+            }
+            if m.starts_with("UPDATE") {
+                stats.updates += 1;
+                update_count += 1
+            }
         }
     }
     println!("update_count: {update_count}");
+    print_clients(clients);
+    print_latencies(latencies);
+}
+
+// The lines of the traffic, without empty ones. Non-ASCII lines are given by their length.
+fn messages(bytes: &[u8]) -> impl Iterator<Item = Result<&str, usize>> {
+    bytes
+        .split(|&b| b == b'\n')
+        .filter(|message| !message.is_empty())
+        .map(|message| from_ascii(message).ok_or(message.len()))
+}
+
+// Renders an event the way `spy decipher` prints it, given its decoded traffic.
+fn decipher(record: &Record) -> String {
+    let prompt = match record.type_ {
+        Type_::Send => ">>",
+        Type_::Recv => "<<",
+        Type_::Meta => match record.metadata() {
+            Some(metadata) => return format!("-- {metadata}\n"),
+            None => return format!("-- invalid metadata: {}\n", record.text()),
+        },
+        _ => return format!("-- connection {}: {}\n", record.id, record.text()),
+    };
+    let mut text = format!("{prompt} connection {}\n", record.id);
+    for message in messages(&record.message) {
+        match message {
+            Ok(m) => text.push_str(m),
+            Err(len) => text.push_str(&format!("non-ascii message of length {len}")),
+        }
+        text.push('\n');
+    }
+    text
+}
+
+fn print_clients(clients: BTreeMap<String, ClientStats>) {
+    if clients.keys().all(|client| client == "unknown") {
+        return;
+    }
+    println!(
+        "{:<24} {:>12} {:>12} {:>12}",
+        "client", "connections", "commands", "updates"
+    );
+    for (client, stats) in clients {
+        println!(
+            "{:<24} {:>12} {:>12} {:>12}",
+            client, stats.connections, stats.commands, stats.updates
+        );
+    }
+}
+
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn print_latencies(latencies: BTreeMap<String, Vec<u64>>) {
+    if latencies.is_empty() {
+        return;
+    }
+    println!(
+        "{:<12} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "latency(us)", "count", "p50", "p90", "p99", "max"
+    );
+    for (command, mut values) in latencies {
+        values.sort_unstable();
+        println!(
+            "{:<12} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            command,
+            values.len(),
+            percentile(&values, 0.5),
+            percentile(&values, 0.9),
+            percentile(&values, 0.99),
+            values[values.len() - 1],
+        );
+    }
+}
+
+fn convert_main(stdout_filter: EnvFilter, input: &Path, output: &Path, format: Format) {
+    let stdout_layer = fmt::Layer::default().compact().with_filter(stdout_filter);
+    tracing_subscriber::registry().with(stdout_layer).init();
+    if output.exists() {
+        panic!("user error, output file exists: {}", output.display());
+    }
+    let writer = Output::open(output, None, None, format, None).unwrap();
+    let mut count = 0u64;
+    for record in format::recording(input, Window::default()) {
+        writer.write(&record).unwrap();
+        count += 1;
+    }
+    writer.flush().unwrap();
+    info!("converted {count} record(s) to {}", output.display());
+}
+
+fn export_pcap_main(stdout_filter: EnvFilter, input: &Path, output: &Path) {
+    let stdout_layer = fmt::Layer::default().compact().with_filter(stdout_filter);
+    tracing_subscriber::registry().with(stdout_layer).init();
+    if output.exists() {
+        panic!("user error, output file exists: {}", output.display());
+    }
+    let file = io::BufWriter::new(fs::File::create(output).unwrap());
+    let packets = pcap::export(format::recording(input, Window::default()), file).unwrap();
+    info!("exported {packets} packet(s) to {}", output.display());
+}
+
+fn index_main(stdout_filter: EnvFilter, input: &Path) {
+    let stdout_layer = fmt::Layer::default().compact().with_filter(stdout_filter);
+    tracing_subscriber::registry().with(stdout_layer).init();
+    for part in format::parts(input) {
+        let entries = index::build(&part).unwrap();
+        index::write(&part, &entries).unwrap();
+        info!("indexed {}: {} chunk(s)", part.display(), entries.len());
+    }
+}
+
+fn control_main(pidfile: &Path, request: &str) {
+    if let Err(e) = control::query(pidfile, request) {
+        panic!(
+            "user error, could not reach spy at {}: {e}",
+            control::path(pidfile).display()
+        );
+    }
 }
 
 fn main() {
@@ -322,12 +1334,73 @@ fn main() {
         _ => "trace",
     });
     match arguments.command {
-        Command::Record {
-            output,
+        Command::Record(args) => record_main(filter, *args),
+        Command::Replay {
+            input,
             socket,
-            pidfile,
-        } => record_main(filter, &output, &socket, &pidfile),
-        Command::Replay { input, socket } => replay_main(filter, &input, &socket),
-        Command::Decipher { input } => decipher_main(filter, &input),
+            rrd_dir,
+            window,
+        } => replay_main(filter, &input, &socket, &rrd_dir, window),
+        Command::Decipher {
+            input,
+            program,
+            window,
+        } => decipher_main(filter, &input, program.as_ref(), window),
+        Command::Convert {
+            input,
+            output,
+            format,
+        } => convert_main(filter, &input, &output, format),
+        Command::ExportPcap { input, output } => export_pcap_main(filter, &input, &output),
+        Command::Index { input } => index_main(filter, &input),
+        Command::Restore { socket, alternate } => restore_main(filter, &socket, alternate),
+        Command::Status { pidfile } => control_main(&pidfile, "STATUS"),
+        Command::Stats { pidfile } => control_main(&pidfile, "STATS"),
+        Command::Tail { pidfile } => control_main(&pidfile, "TAIL"),
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64K"), Ok(64 << 10));
+        assert_eq!(parse_size("2M"), Ok(2 << 20));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("0K").is_err());
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("-1").is_err());
+        assert_eq!(
+            parse_size("18014398509481984K"),
+            Err("18014398509481984K is too large".into())
+        );
+        assert_eq!(
+            parse_size(&format!("{}G", u64::MAX >> 30)),
+            Ok(u64::MAX >> 30 << 30)
+        );
+    }
+
+    #[test]
+    fn intervals() {
+        assert_eq!(parse_interval("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_interval("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_interval("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_interval("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_interval("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_interval("2d"), Ok(Duration::from_secs(2 * 86400)));
+        assert!(parse_interval("0").is_err());
+        assert!(parse_interval("0ms").is_err());
+        assert!(parse_interval("1w").is_err());
+        assert!(parse_interval("").is_err());
+        let days = u64::MAX / (24 * 60 * 60 * 1000) + 1;
+        assert_eq!(
+            parse_interval(&format!("{days}d")),
+            Err(format!("{days}d is too long"))
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use chrono::{DateTime, Utc};
+use clickhouse::Row;
+use serde::{Deserialize, Serialize};
+
+// The `metrics` table, as loaded by `replay_clickhouse` and `spy record --tee`.
+pub const CLICKHOUSE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS metrics (
+      path String,
+      name UInt64,
+      time DateTime64(3, 'UTC'),
+      value  Nullable(Float64)
+    )
+    ENGINE = MergeTree
+    PRIMARY KEY (path, name, time)
+    ";
+
+// The `metrics` hypertable, as loaded by `replay` and `spy record --tee`.
+pub const POSTGRES_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS metrics (
+      partition BIGINT            NOT NULL,
+      name      BIGINT            NOT NULL,
+      time      TIMESTAMPTZ       NOT NULL,
+      value     DOUBLE PRECISION,
+      PRIMARY KEY (partition, name, time)
+    ) WITH (
+      timescaledb.hypertable,
+      timescaledb.partition_column='time',
+      timescaledb.segmentby='partition'
+    );
+    ";
+
+#[derive(Serialize)]
+pub struct UpdateMessage {
+    pub time: i64,
+    pub path: String,
+    pub metrics: Vec<Option<f64>>,
+}
+
+impl UpdateMessage {
+    pub fn time(&self) -> DateTime<Utc> {
+        DateTime::<Utc>::from_timestamp(self.time, 0).expect("Invalid UNIX timestamp")
+    }
+
+    // One row per data source, which is named by its position in the update.
+    pub fn rows(&self) -> impl Iterator<Item = MyRow> {
+        let time = self.time();
+        self.metrics.iter().zip(0..).map(move |(metric, i)| MyRow {
+            path: self.path.clone(),
+            name: i,
+            time,
+            value: *metric,
+        })
+    }
+
+    // One `VALUES` tuple per data source for the Postgres `metrics` table. `NaN` and `inf` have
+    // no literal in SQL and are stored as unknown.
+    pub fn values(&self, partition: i64) -> impl Iterator<Item = String> {
+        let time = self.time();
+        self.metrics
+            .iter()
+            .zip(0..)
+            .map(move |(metric, i)| match metric {
+                Some(value) if value.is_finite() => {
+                    format!("({partition}, {i}, '{time}', {value})")
+                }
+                _ => format!("({partition}, {i}, '{time}', NULL)"),
+            })
+    }
+}
+
+#[derive(Row, Serialize, Deserialize, Debug)]
+pub struct MyRow {
+    pub path: String,
+    pub name: u64,
+    #[serde(with = "clickhouse::serde::chrono::datetime64::millis")]
+    pub time: DateTime<Utc>,
+    pub value: Option<f64>,
+}
+
+fn parse_float(metric: &str) -> anyhow::Result<Option<f64>> {
+    if metric == "U" {
+        return Ok(None);
+    }
+    Ok(Some(metric.parse()?))
+}
+
+fn parse_time(time: &str, now: DateTime<Utc>) -> anyhow::Result<i64> {
+    if time == "N" {
+        return Ok(now.timestamp());
+    }
+    Ok(time.parse()?)
+}
+
+// Parses an UPDATE, which was sent at `now`. That is the time of the record for a recording and
+// the wall clock for live traffic.
+pub fn parse(message: &str, now: DateTime<Utc>) -> anyhow::Result<UpdateMessage> {
+    let mut parts = message.trim_end().splitn(3, ' ');
+    let _command = parts.next().unwrap();
+    let path = parts
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("missing path"))?;
+    let data_str = parts
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("missing values"))?;
+    let mut data_values = data_str.split(':');
+    let timestamp = parse_time(data_values.next().unwrap(), now)?;
+    let metrics: anyhow::Result<Vec<Option<f64>>> = data_values.map(parse_float).collect();
+
+    Ok(UpdateMessage {
+        time: timestamp,
+        path: path.into(),
+        metrics: metrics?,
+    })
+}
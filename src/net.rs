@@ -0,0 +1,243 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt, chown};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use crate::recording::Peer;
+
+// Address specification as accepted by the `-l` flag of rrdcached: `unix:<path>` or an absolute
+// path is a unix socket, anything else is `<host>[:<port>]`, optionally prefixed by `tcp:`.
+#[derive(Debug, Clone)]
+pub enum Address {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+// The port rrdcached listens on, if the address doesn't name one.
+const DEFAULT_PORT: u16 = 42217;
+
+// Adds the default port to `host`, `[ipv6]` or a bare IPv6 address.
+fn host_port(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Err("expected an address".into());
+    }
+    if s.contains('/') {
+        return Err(format!(
+            "expected unix:<path>, an absolute path or <host>[:<port>], got {s:?}; \
+             use unix:{s} for a relative path"
+        ));
+    }
+    if s.starts_with('[') {
+        return match s.split_once(']') {
+            Some((_, "")) => Ok(format!("{s}:{DEFAULT_PORT}")),
+            Some((_, port)) if port.starts_with(':') => Ok(s.into()),
+            _ => Err(format!("expected [<ipv6>]:<port>, got {s}")),
+        };
+    }
+    match s.matches(':').count() {
+        0 => Ok(format!("{s}:{DEFAULT_PORT}")),
+        1 => Ok(s.into()),
+        _ => Ok(format!("[{s}]:{DEFAULT_PORT}")),
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Address::Unix(path.into()));
+        }
+        if s.starts_with('/') {
+            return Ok(Address::Unix(s.into()));
+        }
+        let host_port = host_port(s.strip_prefix("tcp:").unwrap_or(s))?;
+        Ok(Address::Tcp(host_port))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Unix(path) => write!(f, "unix:{}", path.to_string_lossy()),
+            Address::Tcp(host_port) => write!(f, "tcp:{host_port}"),
+        }
+    }
+}
+
+impl Address {
+    pub async fn connect(&self) -> io::Result<Stream> {
+        match self {
+            Address::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path).await?)),
+            Address::Tcp(host_port) => Ok(Stream::Tcp(TcpStream::connect(host_port).await?)),
+        }
+    }
+
+    pub async fn bind(&self) -> io::Result<Listener> {
+        match self {
+            Address::Unix(path) => Ok(Listener::Unix(UnixListener::bind(path)?)),
+            Address::Tcp(host_port) => Ok(Listener::Tcp(TcpListener::bind(host_port).await?)),
+        }
+    }
+}
+
+// Removes a unix socket left behind by a process, which is gone. Anything else at the path,
+// including a socket somebody still answers on, is left alone.
+pub fn remove_stale(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::other("not a socket"));
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::other("in use")),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+// Owner, group and mode of a unix socket. rrdcached is usually started with `-s <group> -m 660`,
+// which the replacement socket has to match, so that clients of other users can connect.
+#[derive(Debug, Clone, Default)]
+pub struct Permissions {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub mode: Option<u32>,
+}
+
+impl Permissions {
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+        Ok(Self {
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            mode: Some(metadata.mode() & 0o7777),
+        })
+    }
+
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        chown(path, self.uid, self.gid)?;
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+}
+
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    // Takes over a listening socket, which was opened by someone else, e.g., systemd.
+    pub fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+        let unix = std::os::unix::net::UnixListener::from(fd);
+        if unix.local_addr().is_ok() {
+            unix.set_nonblocking(true)?;
+            return Ok(Listener::Unix(UnixListener::from_std(unix)?));
+        }
+        let tcp = std::net::TcpListener::from(OwnedFd::from(unix));
+        tcp.set_nonblocking(true)?;
+        Ok(Listener::Tcp(TcpListener::from_std(tcp)?))
+    }
+
+    pub async fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Unix(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Unix(stream), format!("{addr:?}")))
+            }
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), addr.to_string()))
+            }
+        }
+    }
+}
+
+pub enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    // Identifies the connecting process. Only available for unix sockets.
+    pub fn peer(&self) -> Option<Peer> {
+        let Stream::Unix(stream) = self else {
+            return None;
+        };
+        let cred = stream.peer_cred().ok()?;
+        let proc = |name: &str| cred.pid().map(|pid| format!("/proc/{pid}/{name}"));
+        let exe = proc("exe")
+            .and_then(|path| fs::read_link(path).ok())
+            .map(|exe| exe.to_string_lossy().into_owned());
+        let cmdline = proc("cmdline")
+            .and_then(|path| fs::read(path).ok())
+            .map(|cmdline| {
+                cmdline
+                    .split(|&b| b == 0)
+                    .filter(|arg| !arg.is_empty())
+                    .map(String::from_utf8_lossy)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            });
+        Some(Peer {
+            pid: cred.pid(),
+            uid: cred.uid(),
+            gid: cred.gid(),
+            exe,
+            cmdline,
+        })
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
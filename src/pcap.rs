@@ -0,0 +1,361 @@
+use prototype::format::Record;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::iter::Peekable;
+
+use crate::recording::Type_;
+
+// Every connection becomes a TCP stream from 127.0.0.2 to the rrdcached port of 127.0.0.1, with
+// a client port derived from the id. Wireshark copes with ports being reused after a while.
+const CLIENT: [u8; 4] = [127, 0, 0, 2];
+const SERVER: [u8; 4] = [127, 0, 0, 1];
+const SERVER_PORT: u16 = 42217;
+// Keeps every segment below the maximum size of an IPv4 packet.
+const MAX_SEGMENT: usize = 65000;
+
+const SECTION_HEADER: u32 = 0x0a0d0d0a;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const LINKTYPE_RAW: u16 = 101;
+const OPT_COMMENT: u16 = 1;
+const OPT_NAME: u16 = 2;
+const OPT_USER_APPLICATION: u16 = 4;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+fn end_of_options(body: &mut Vec<u8>) {
+    body.extend_from_slice(&[0; 4]);
+}
+
+fn block(out: &mut impl Write, type_: u32, body: &[u8]) -> io::Result<()> {
+    let total = (body.len() + 12) as u32;
+    out.write_all(&type_.to_le_bytes())?;
+    out.write_all(&total.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&total.to_le_bytes())
+}
+
+fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in chunks {
+        for pair in chunk.chunks(2) {
+            let word = match pair {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                [high] => u16::from_be_bytes([*high, 0]),
+                _ => unreachable!(),
+            };
+            sum += word as u32;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// The TCP state of a synthesized stream. Sequence numbers start at 0.
+struct Stream {
+    port: u16,
+    client_seq: u32,
+    server_seq: u32,
+    client_fin: bool,
+    server_fin: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Segment<'a> {
+    from_client: bool,
+    flags: u8,
+    payload: &'a [u8],
+}
+
+impl Stream {
+    fn new(id: u64) -> Self {
+        Self {
+            port: 1024 + (id % 64512) as u16,
+            client_seq: 0,
+            server_seq: 0,
+            client_fin: false,
+            server_fin: false,
+        }
+    }
+
+    // Builds the IPv4 packet of a segment and advances the sequence number of its sender.
+    fn packet(&mut self, segment: Segment) -> Vec<u8> {
+        let (src, dst, sport, dport, seq, ack) = match segment.from_client {
+            true => (
+                CLIENT,
+                SERVER,
+                self.port,
+                SERVER_PORT,
+                self.client_seq,
+                self.server_seq,
+            ),
+            false => (
+                SERVER,
+                CLIENT,
+                SERVER_PORT,
+                self.port,
+                self.server_seq,
+                self.client_seq,
+            ),
+        };
+        let ack = if segment.flags & ACK == 0 { 0 } else { ack };
+        let mut tcp = Vec::with_capacity(20 + segment.payload.len());
+        tcp.extend_from_slice(&sport.to_be_bytes());
+        tcp.extend_from_slice(&dport.to_be_bytes());
+        tcp.extend_from_slice(&seq.to_be_bytes());
+        tcp.extend_from_slice(&ack.to_be_bytes());
+        tcp.extend_from_slice(&[5 << 4, segment.flags]);
+        tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+        tcp.extend_from_slice(&[0; 4]);
+        tcp.extend_from_slice(segment.payload);
+        let pseudo = [
+            &src[..],
+            &dst[..],
+            &[0, 6],
+            &(tcp.len() as u16).to_be_bytes(),
+        ]
+        .concat();
+        let sum = checksum(&[&pseudo, &tcp]);
+        tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+        let mut ip = vec![0x45, 0];
+        ip.extend_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+        ip.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+        ip.extend_from_slice(&src);
+        ip.extend_from_slice(&dst);
+        let sum = checksum(&[&ip]);
+        ip[10..12].copy_from_slice(&sum.to_be_bytes());
+        ip.extend_from_slice(&tcp);
+        let consumed = segment.payload.len() as u32 + (segment.flags & (SYN | FIN) != 0) as u32;
+        match segment.from_client {
+            true => self.client_seq = self.client_seq.wrapping_add(consumed),
+            false => self.server_seq = self.server_seq.wrapping_add(consumed),
+        }
+        ip
+    }
+}
+
+struct Writer<W: Write> {
+    out: W,
+    streams: HashMap<u64, Stream>,
+    packets: u64,
+}
+
+impl<W: Write> Writer<W> {
+    fn write(
+        &mut self,
+        record: &Record,
+        segments: &[Segment],
+        mut comment: Option<String>,
+    ) -> io::Result<()> {
+        let stream = self
+            .streams
+            .entry(record.id)
+            .or_insert_with(|| Stream::new(record.id));
+        let micros = record.timestamp.timestamp_micros() as u64;
+        for segment in segments {
+            let packet = stream.packet(*segment);
+            let mut body = Vec::with_capacity(packet.len() + 64);
+            body.extend_from_slice(&0u32.to_le_bytes());
+            body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+            body.extend_from_slice(&(micros as u32).to_le_bytes());
+            body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            body.extend_from_slice(&packet);
+            pad(&mut body);
+            if let Some(comment) = comment.take() {
+                option(&mut body, OPT_COMMENT, comment.as_bytes());
+                end_of_options(&mut body);
+            }
+            block(&mut self.out, ENHANCED_PACKET, &body)?;
+            self.packets += 1;
+        }
+        Ok(())
+    }
+
+    // Recordings from before `open` start with the traffic, so the handshake is made up then.
+    fn opened(&mut self, record: &Record) -> io::Result<()> {
+        match self.streams.contains_key(&record.id) {
+            true => Ok(()),
+            false => self.open(record, None),
+        }
+    }
+
+    fn open(&mut self, record: &Record, comment: Option<String>) -> io::Result<()> {
+        self.streams.insert(record.id, Stream::new(record.id));
+        let handshake = [
+            Segment {
+                from_client: true,
+                flags: SYN,
+                payload: &[],
+            },
+            Segment {
+                from_client: false,
+                flags: SYN | ACK,
+                payload: &[],
+            },
+            Segment {
+                from_client: true,
+                flags: ACK,
+                payload: &[],
+            },
+        ];
+        self.write(record, &handshake, comment)
+    }
+
+    fn traffic(&mut self, record: &Record) -> io::Result<()> {
+        self.opened(record)?;
+        let from_client = record.type_ == Type_::Send;
+        if record.message.is_empty() {
+            return self.fin(record, from_client, None);
+        }
+        let segments: Vec<Segment> = record
+            .message
+            .chunks(MAX_SEGMENT)
+            .map(|payload| Segment {
+                from_client,
+                flags: PSH | ACK,
+                payload,
+            })
+            .collect();
+        self.write(record, &segments, None)
+    }
+
+    fn fin(
+        &mut self,
+        record: &Record,
+        from_client: bool,
+        comment: Option<String>,
+    ) -> io::Result<()> {
+        let stream = self.streams.get_mut(&record.id).unwrap();
+        let finished = match from_client {
+            true => &mut stream.client_fin,
+            false => &mut stream.server_fin,
+        };
+        if *finished {
+            return self.note(record, comment);
+        }
+        *finished = true;
+        let fin = Segment {
+            from_client,
+            flags: FIN | ACK,
+            payload: &[],
+        };
+        let ack = Segment {
+            from_client: !from_client,
+            flags: ACK,
+            payload: &[],
+        };
+        self.write(record, &[fin, ack], comment)
+    }
+
+    // Closes what is still open of the stream, noting the reason.
+    fn close(&mut self, record: &Record) -> io::Result<()> {
+        self.opened(record)?;
+        let comment = Some(format!("close: {}", record.text()));
+        let stream = &self.streams[&record.id];
+        match (stream.client_fin, stream.server_fin) {
+            (false, _) => {
+                self.fin(record, true, comment)?;
+                self.fin(record, false, None)?;
+            }
+            (true, false) => self.fin(record, false, comment)?,
+            (true, true) => self.note(record, comment)?,
+        }
+        self.streams.remove(&record.id);
+        Ok(())
+    }
+
+    // Notes a record without traffic as the comment of an empty segment from the client.
+    fn note(&mut self, record: &Record, comment: Option<String>) -> io::Result<()> {
+        let Some(comment) = comment else {
+            return Ok(());
+        };
+        let ack = Segment {
+            from_client: true,
+            flags: ACK,
+            payload: &[],
+        };
+        self.write(record, &[ack], Some(comment))
+    }
+}
+
+fn comment(record: &Record) -> String {
+    let type_ = format!("{:?}", record.type_).to_lowercase();
+    match record.latency_us {
+        Some(latency_us) => format!("{type_}: {} took {latency_us} µs", record.text()),
+        None => format!("{type_}: {}", record.text()),
+    }
+}
+
+// Writes the section header, which carries the metadata of the recording as comments, and the
+// single interface all packets are captured on.
+fn header<I: Iterator<Item = Record>>(
+    out: &mut impl Write,
+    records: &mut Peekable<I>,
+) -> io::Result<()> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&0x1a2b3c4du32.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    let metadata = records.peek().and_then(Record::metadata);
+    if let Some(metadata) = &metadata {
+        option(&mut body, OPT_COMMENT, metadata.to_string().as_bytes());
+        for label in &metadata.labels {
+            option(&mut body, OPT_COMMENT, format!("label {label}").as_bytes());
+        }
+    }
+    let application = format!("spy {}", env!("CARGO_PKG_VERSION"));
+    option(&mut body, OPT_USER_APPLICATION, application.as_bytes());
+    end_of_options(&mut body);
+    block(out, SECTION_HEADER, &body)?;
+    let mut body = Vec::new();
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    let name = metadata.map_or("spy".into(), |metadata| metadata.socket);
+    option(&mut body, OPT_NAME, name.as_bytes());
+    end_of_options(&mut body);
+    block(out, INTERFACE_DESCRIPTION, &body)
+}
+
+// Writes the records as pcapng for Wireshark and returns the number of packets.
+pub fn export(records: impl Iterator<Item = Record>, out: impl Write) -> io::Result<u64> {
+    let mut records = records.peekable();
+    let mut writer = Writer {
+        out,
+        streams: HashMap::new(),
+        packets: 0,
+    };
+    header(&mut writer.out, &mut records)?;
+    for record in records {
+        match record.type_ {
+            Type_::Meta => (),
+            Type_::Open => writer.open(&record, Some(comment(&record)))?,
+            Type_::Send | Type_::Recv => writer.traffic(&record)?,
+            Type_::Close => writer.close(&record)?,
+            Type_::Connect | Type_::Error | Type_::Latency | Type_::Fault => {
+                writer.opened(&record)?;
+                writer.note(&record, Some(comment(&record)))?;
+            }
+        }
+    }
+    writer.out.flush()?;
+    Ok(writer.packets)
+}
@@ -0,0 +1,202 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{interval, timeout};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+use crate::Proxy;
+use crate::tee::Tee;
+
+const TEXTFILE_INTERVAL: Duration = Duration::from_secs(15);
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+fn metric(text: &mut String, name: &str, type_: &str, help: &str, value: impl std::fmt::Display) {
+    writeln!(text, "# HELP {name} {help}").unwrap();
+    writeln!(text, "# TYPE {name} {type_}").unwrap();
+    writeln!(text, "{name} {value}").unwrap();
+}
+
+fn by_command<'a>(
+    text: &mut String,
+    name: &str,
+    help: &str,
+    values: impl Iterator<Item = (&'a str, u64)>,
+) {
+    writeln!(text, "# HELP {name} {help}").unwrap();
+    writeln!(text, "# TYPE {name} counter").unwrap();
+    for (command, value) in values {
+        let command = escape(command);
+        writeln!(text, "{name}{{command=\"{command}\"}} {value}").unwrap();
+    }
+}
+
+// Renders what spy sees in the Prometheus text format.
+pub fn render(proxy: &Proxy) -> String {
+    let totals = proxy.stats.totals();
+    let recorder = &proxy.recorder;
+    let mut text = String::new();
+    let metrics = [
+        (
+            "spy_connections_accepted_total",
+            "counter",
+            "Connections accepted from clients.",
+            totals.accepted,
+        ),
+        (
+            "spy_connections_open",
+            "gauge",
+            "Connections currently forwarded.",
+            totals.open as u64,
+        ),
+        (
+            "spy_sent_bytes_total",
+            "counter",
+            "Bytes sent by clients.",
+            totals.sent,
+        ),
+        (
+            "spy_received_bytes_total",
+            "counter",
+            "Bytes received by clients.",
+            totals.received,
+        ),
+        (
+            "spy_upstream_up",
+            "gauge",
+            "Whether the last connection to the upstream succeeded.",
+            totals.upstream_up as u64,
+        ),
+        (
+            "spy_upstream_errors_total",
+            "counter",
+            "Failed connection attempts to the upstream.",
+            totals.upstream_errors,
+        ),
+        (
+            "spy_recording",
+            "gauge",
+            "Whether recording is on, i.e., not paused.",
+            recorder.recording() as u64,
+        ),
+        (
+            "spy_recorder_queue_depth",
+            "gauge",
+            "Records waiting to be written.",
+            recorder.queued() as u64,
+        ),
+        (
+            "spy_recorder_dropped_total",
+            "counter",
+            "Records dropped because the queue was full.",
+            recorder.dropped(),
+        ),
+    ];
+    for (name, type_, help, value) in metrics {
+        metric(&mut text, name, type_, help, value);
+    }
+    if let Some(dropped) = proxy.tee.as_ref().map(Tee::dropped) {
+        let help = "Updates not teed because the queue was full.";
+        metric(&mut text, "spy_tee_dropped_total", "counter", help, dropped);
+    }
+    let commands = &totals.commands;
+    let help = "Commands sent by clients, e.g., the rate of UPDATEs.";
+    let counts = commands.iter().map(|(command, c)| (*command, c.count));
+    by_command(&mut text, "spy_commands_total", help, counts);
+    let help = "Bytes of the commands sent by clients.";
+    let bytes = commands.iter().map(|(command, c)| (*command, c.bytes));
+    by_command(&mut text, "spy_command_bytes_total", help, bytes);
+    text
+}
+
+async fn respond(mut stream: TcpStream, proxy: &Proxy) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let (status, body) = match line {
+        b"GET /metrics HTTP/1.0" | b"GET /metrics HTTP/1.1" => ("200 OK", render(proxy)),
+        _ => ("404 Not Found", "try /metrics\n".into()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// Serves `/metrics` for Prometheus. Scrapes are rare and small, so each is answered in turn,
+// and a slow client is given up on after a while.
+pub async fn serve(token: CancellationToken, addr: SocketAddr, proxy: Arc<Proxy>) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("could not serve metrics at {addr}: {e:?}");
+            return;
+        }
+    };
+    info!("serving metrics at http://{addr}/metrics");
+    loop {
+        let stream = tokio::select! {
+            accept = listener.accept() => match accept {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("could not accept metrics connection: {e:?}");
+                    continue;
+                }
+            },
+            _ = token.cancelled() => break,
+        };
+        match timeout(SCRAPE_TIMEOUT, respond(stream, &proxy)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => debug!("metrics connection failed: {e:?}"),
+            Err(_) => debug!("metrics connection timed out"),
+        }
+    }
+}
+
+fn write(path: &Path, proxy: &Proxy) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, render(proxy))?;
+    fs::rename(&tmp, path)
+}
+
+// Writes the metrics for the textfile collector of node_exporter. The file is replaced
+// atomically, so the collector never reads a partial file.
+pub async fn write_textfile(token: CancellationToken, path: PathBuf, proxy: Arc<Proxy>) {
+    info!("writing metrics to {}", path.display());
+    let mut interval = interval(TEXTFILE_INTERVAL);
+    loop {
+        let stopped = tokio::select! {
+            _ = interval.tick() => false,
+            _ = token.cancelled() => true,
+        };
+        if let Err(e) = write(&path, &proxy) {
+            error!("could not write metrics to {}: {e:?}", path.display());
+        }
+        if stopped {
+            break;
+        }
+    }
+}
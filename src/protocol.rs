@@ -0,0 +1,212 @@
+use std::collections::VecDeque;
+use tokio::time::Instant;
+
+// Commands, whose first argument is the RRD file.
+const FILE_COMMANDS: [&str; 10] = [
+    "UPDATE", "FLUSH", "FETCH", "FETCHBIN", "FORGET", "PENDING", "FIRST", "LAST", "INFO", "CREATE",
+];
+
+// The commands rrdcached knows, including the dot ending a BATCH.
+pub const COMMANDS: [&str; 18] = [
+    ".", "BATCH", "CREATE", "FETCH", "FETCHBIN", "FIRST", "FLUSH", "FLUSHALL", "FORGET", "HELP",
+    "INFO", "LAST", "LIST", "PENDING", "QUEUE", "QUIT", "STATS", "UPDATE",
+];
+
+#[derive(Default)]
+struct LineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LineBuffer {
+    // Returns the complete lines, including the trailing newline.
+    fn push(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(bytes);
+        std::iter::from_fn(|| self.line()).collect()
+    }
+
+    fn line(&mut self) -> Option<Vec<u8>> {
+        let end = self.pending.iter().position(|&b| b == b'\n')?;
+        Some(self.pending.drain(..=end).collect())
+    }
+
+    // Returns up to `len` bytes regardless of newlines.
+    fn take(&mut self, len: usize) -> Vec<u8> {
+        let len = len.min(self.pending.len());
+        self.pending.drain(..len).collect()
+    }
+}
+
+// In the answer to FETCHBIN, the header of each data set, e.g., `DSName-load: data-type=DOUBLE,
+// elements=60, element-size=8, endianess=LITTLE`, is followed by that many raw bytes and a
+// newline. The raw bytes may contain newlines of their own.
+fn binary_len(line: &[u8]) -> usize {
+    let Some(header) = line.strip_prefix(b"DSName-") else {
+        return 0;
+    };
+    let header = String::from_utf8_lossy(header);
+    let field = |name: &str| {
+        header
+            .split([':', ','])
+            .find_map(|field| field.trim().strip_prefix(name)?.parse::<usize>().ok())
+    };
+    match (field("elements="), field("element-size=")) {
+        (Some(elements), Some(size)) => elements.saturating_mul(size).saturating_add(1),
+        _ => 0,
+    }
+}
+
+pub struct Request {
+    pub line: Vec<u8>,
+    pub command: String,
+    pub path: Option<String>,
+    pub answered: bool,
+}
+
+impl Request {
+    fn parse(line: Vec<u8>, answered: bool) -> Self {
+        let text = String::from_utf8_lossy(&line);
+        let mut words = text.split_whitespace();
+        let command = words.next().unwrap_or_default().to_ascii_uppercase();
+        let path = match FILE_COMMANDS.contains(&command.as_str()) {
+            true => words.next().map(str::to_string),
+            false => None,
+        };
+        Self {
+            line,
+            command,
+            path,
+            answered,
+        }
+    }
+}
+
+pub struct Pending {
+    pub command: String,
+    pub sent: Instant,
+    pub recorded: bool,
+}
+
+pub struct Response {
+    pub bytes: Vec<u8>,
+    pub request: Option<Pending>,
+}
+
+// Splits the traffic of one connection into requests and responses. rrdcached answers every
+// command with a status line, followed by as many lines as a positive status announces, and
+// the data sets of FETCHBIN framed by their size. Commands inside a BATCH are only answered once
+// the batch is terminated by a single dot.
+#[derive(Default)]
+pub struct Session {
+    requests: LineBuffer,
+    responses: LineBuffer,
+    batch: bool,
+    pending: VecDeque<Pending>,
+    remaining: usize,
+    binary: usize,
+    response: Vec<u8>,
+}
+
+impl Session {
+    pub fn requests(&mut self, bytes: &[u8]) -> Vec<Request> {
+        let mut requests = Vec::new();
+        for line in self.requests.push(bytes) {
+            let answered = !self.batch || line.trim_ascii_end() == b".";
+            let request = Request::parse(line, answered);
+            match request.command.as_str() {
+                "BATCH" => self.batch = true,
+                "." => self.batch = false,
+                _ => (),
+            }
+            requests.push(request);
+        }
+        requests
+    }
+
+    pub fn expect(&mut self, pending: Pending) {
+        self.pending.push_back(pending);
+    }
+
+    pub fn responses(&mut self, bytes: &[u8]) -> Vec<Response> {
+        self.responses.pending.extend_from_slice(bytes);
+        let mut responses = Vec::new();
+        loop {
+            if self.binary > 0 {
+                let data = self.responses.take(self.binary);
+                if data.is_empty() {
+                    break;
+                }
+                self.binary -= data.len();
+                self.response.extend_from_slice(&data);
+                self.complete(&mut responses);
+                continue;
+            }
+            let Some(line) = self.responses.line() else {
+                break;
+            };
+            if self.response.is_empty() {
+                let status = line.split(|&b| b == b' ').next().unwrap_or_default();
+                self.remaining = std::str::from_utf8(status)
+                    .ok()
+                    .and_then(|status| status.trim_ascii_end().parse::<i64>().ok())
+                    .map_or(0, |status| status.max(0) as usize);
+            } else {
+                self.remaining = self.remaining.saturating_sub(1);
+                self.binary = binary_len(&line);
+            }
+            self.response.extend_from_slice(&line);
+            self.complete(&mut responses);
+        }
+        responses
+    }
+
+    fn complete(&mut self, responses: &mut Vec<Response>) {
+        if self.remaining == 0 && self.binary == 0 {
+            responses.push(Response {
+                bytes: std::mem::take(&mut self.response),
+                request: self.pending.pop_front(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fetchbin(data: &[u8]) -> Vec<u8> {
+        let header = "6 Success\nFlushVersion: 1\nStart: 1700000000\nEnd: 1700000120\n\
+                      Step: 60\nDSCount: 1\n\
+                      DSName-load: data-type=DOUBLE, elements=2, element-size=8, endianess=LITTLE\n";
+        [header.as_bytes(), data, b"\n"].concat()
+    }
+
+    #[test]
+    fn fetchbin_data_may_contain_newlines() {
+        let data = [
+            10.0f64.to_le_bytes(),
+            f64::from_le_bytes([b'\n'; 8]).to_le_bytes(),
+        ]
+        .concat();
+        let first = fetchbin(&data);
+        let second = b"0 Nothing to flush: /b.rrd\n";
+        let mut session = Session::default();
+        let requests = session.requests(b"FETCHBIN /a.rrd AVERAGE\nFLUSH /b.rrd\n");
+        for request in requests {
+            session.expect(Pending {
+                command: request.command,
+                sent: Instant::now(),
+                recorded: true,
+            });
+        }
+        let traffic = [&first[..], second].concat();
+        let mut responses = Vec::new();
+        for byte in traffic.chunks(1) {
+            responses.extend(session.responses(byte));
+        }
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].bytes, first);
+        assert_eq!(responses[0].request.as_ref().unwrap().command, "FETCHBIN");
+        assert_eq!(responses[1].bytes, second);
+        assert_eq!(responses[1].request.as_ref().unwrap().command, "FLUSH");
+    }
+}
@@ -0,0 +1,138 @@
+use prototype::format::Record;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Receiver, Sender, channel};
+use tracing::{error, info, warn};
+
+use crate::recording::{Output, Peer, Type_};
+
+// Hands events to a background thread, which encodes and writes them. The proxy never waits
+// for the recorder: if the queue is full, the event is dropped and counted instead. Subscribers
+// get a copy of the events, also while recording is paused.
+#[derive(Clone)]
+pub struct Recorder {
+    tx: Sender<Record>,
+    live: broadcast::Sender<Arc<Record>>,
+    recording: Arc<AtomicBool>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Recorder {
+    pub fn spawn(
+        output: Output,
+        capacity: usize,
+        recording: Arc<AtomicBool>,
+    ) -> (Self, thread::JoinHandle<()>) {
+        let (tx, rx) = channel(capacity);
+        let handle = thread::spawn(move || write_events(rx, output));
+        let recorder = Self {
+            tx,
+            live: broadcast::Sender::new(capacity),
+            recording,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (recorder, handle)
+    }
+
+    pub fn bytes(&self, type_: Type_, id: u64, bytes: &[u8]) {
+        if self.wanted() {
+            self.send(Record::new(type_, id, bytes.to_vec()));
+        }
+    }
+
+    pub fn text(&self, type_: Type_, id: u64, text: String) {
+        if self.wanted() {
+            self.send(Record::new(type_, id, text.into_bytes()));
+        }
+    }
+
+    pub fn latency(&self, id: u64, command: String, latency: Duration) {
+        if self.wanted() {
+            self.send(Record {
+                latency_us: Some(latency.as_micros() as u64),
+                ..Record::new(Type_::Latency, id, command.into_bytes())
+            });
+        }
+    }
+
+    // Starts a connection in the recording, with the client process if it is known.
+    pub fn open(&self, id: u64, peer: Option<Peer>, addr: &str) {
+        if self.wanted() {
+            let message = match &peer {
+                Some(peer) => format!(
+                    "client {} (pid {}, uid {}, gid {}): {}",
+                    peer.exe.as_deref().unwrap_or("unknown"),
+                    peer.pid.map_or("unknown".into(), |pid| pid.to_string()),
+                    peer.uid,
+                    peer.gid,
+                    peer.cmdline.as_deref().unwrap_or_default(),
+                ),
+                None => format!("client {addr}"),
+            };
+            self.send(Record {
+                peer,
+                ..Record::new(Type_::Open, id, message.into_bytes())
+            });
+        }
+    }
+
+    pub fn close(&self, id: u64, reason: &str) {
+        self.text(Type_::Close, id, reason.into());
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    pub fn recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Record>> {
+        self.live.subscribe()
+    }
+
+    fn wanted(&self) -> bool {
+        self.recording() || self.live.receiver_count() > 0
+    }
+
+    fn send(&self, record: Record) {
+        if self.live.receiver_count() > 0 {
+            let _ = self.live.send(Arc::new(record.clone()));
+        }
+        if !self.recording() {
+            return;
+        }
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(record) {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("recorder queue is full, dropped {dropped} record(s) so far");
+            }
+        }
+    }
+}
+
+fn write_events(mut rx: Receiver<Record>, output: Output) {
+    while let Some(record) = rx.blocking_recv() {
+        if let Err(e) = output.write(&record) {
+            error!("could not write recording: {e:?}");
+        }
+        if rx.is_empty()
+            && let Err(e) = output.flush()
+        {
+            error!("could not flush recording: {e:?}");
+        }
+    }
+    if let Err(e) = output.flush() {
+        error!("could not flush recording: {e:?}");
+    }
+    info!("recorder stopped");
+}
@@ -0,0 +1,305 @@
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use prototype::format::{self, Format, Record};
+use prototype::index::{self, Chunk};
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+pub use prototype::event::{Peer, Type_};
+
+enum Writer {
+    Json(BufWriter<fs::File>),
+    // The frame being written is compressed into memory and passed on as it grows.
+    Binary {
+        encoder: zstd::Encoder<'static, Vec<u8>>,
+        file: BufWriter<fs::File>,
+    },
+}
+
+fn encoder() -> io::Result<zstd::Encoder<'static, Vec<u8>>> {
+    zstd::Encoder::new(Vec::new(), zstd::DEFAULT_COMPRESSION_LEVEL)
+}
+
+impl Writer {
+    fn write_all(&mut self, record: &[u8]) -> io::Result<()> {
+        match self {
+            Writer::Json(writer) => writer.write_all(record),
+            Writer::Binary { encoder, file } => {
+                encoder.write_all(record)?;
+                file.write_all(encoder.get_ref())?;
+                encoder.get_mut().clear();
+                Ok(())
+            }
+        }
+    }
+
+    // Flushing a binary part ends a zstd block, so everything written so far can be read.
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Json(writer) => writer.flush(),
+            Writer::Binary { encoder, file } => {
+                encoder.flush()?;
+                file.write_all(encoder.get_ref())?;
+                encoder.get_mut().clear();
+                file.flush()
+            }
+        }
+    }
+
+    // Ends the zstd frame, so that reading can start after it, and starts the next one.
+    fn end_frame(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Json(writer) => writer.flush(),
+            Writer::Binary { encoder, file } => {
+                let frame = std::mem::replace(encoder, self::encoder()?).finish()?;
+                file.write_all(&frame)?;
+                file.flush()
+            }
+        }
+    }
+
+    fn file(&self) -> &fs::File {
+        match self {
+            Writer::Json(writer) => writer.get_ref(),
+            Writer::Binary { file, .. } => file.get_ref(),
+        }
+    }
+}
+
+struct Part {
+    path: PathBuf,
+    format: Format,
+    writer: Writer,
+    // The chunk being written and where the next one starts, see `index`.
+    index: fs::File,
+    chunk: Option<Chunk>,
+    offset: u64,
+    buf: Vec<u8>,
+    size: u64,
+    opened: Instant,
+    started: DateTime<Utc>,
+}
+
+impl Part {
+    // Opens a part and writes the metadata record, which doesn't count towards the size, so
+    // that a part without traffic isn't rotated.
+    fn open(path: &Path, format: Format, meta: Option<&Record>) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let size = file.metadata()?.len();
+        let mut writer = BufWriter::with_capacity(1 << 20, file);
+        let writer = match format {
+            Format::Json | Format::Flat => Writer::Json(writer),
+            Format::Binary => {
+                if size == 0 {
+                    writer.write_all(&format::header())?;
+                    writer.flush()?;
+                }
+                Writer::Binary {
+                    encoder: encoder()?,
+                    file: writer,
+                }
+            }
+        };
+        let index = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(size > 0)
+            .truncate(size == 0)
+            .open(index::path(path))?;
+        let mut part = Self {
+            path: path.into(),
+            format,
+            offset: writer.file().metadata()?.len(),
+            writer,
+            index,
+            chunk: None,
+            buf: Vec::new(),
+            size,
+            opened: Instant::now(),
+            started: Utc::now(),
+        };
+        // The metadata is stamped with the opening of the part, which orders rotated parts.
+        if let Some(meta) = meta {
+            let meta = Record {
+                timestamp: part.started,
+                ..meta.clone()
+            };
+            part.append(&meta)?;
+        }
+        Ok(part)
+    }
+
+    // Writes a record and returns its size. Once a chunk is full, it is added to the index.
+    fn append(&mut self, record: &Record) -> io::Result<u64> {
+        self.buf.clear();
+        record.encode(self.format, &mut self.buf);
+        let size = self.buf.len() as u64;
+        self.writer.write_all(&self.buf)?;
+        let chunk = self
+            .chunk
+            .get_or_insert_with(|| Chunk::new(self.offset, record));
+        if chunk.add(record, size) {
+            self.end_chunk()?;
+        }
+        Ok(size)
+    }
+
+    fn end_chunk(&mut self) -> io::Result<()> {
+        let Some(chunk) = self.chunk.take() else {
+            return self.writer.flush();
+        };
+        self.writer.end_frame()?;
+        self.offset = self.writer.file().metadata()?.len();
+        self.index.write_all(index::line(&chunk.entry()).as_bytes())
+    }
+
+    // Ends the last chunk, after which the part can be closed or written on.
+    fn finish(&mut self) -> io::Result<()> {
+        self.end_chunk()
+    }
+}
+
+impl Drop for Part {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!("could not finish {}: {e:?}", self.path.to_string_lossy());
+        }
+    }
+}
+
+fn rotated(path: &Path, started: DateTime<Utc>) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(started.format(".%Y%m%dT%H%M%S%.6fZ").to_string());
+    rotated.into()
+}
+
+// The format of an existing recording, if it has any records.
+fn existing_format(path: &Path) -> io::Result<Option<Format>> {
+    let file = match fs::File::open(path) {
+        Ok(file) if file.metadata()?.len() > 0 => file,
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut reader = BufReader::new(file);
+    match format::detect(&mut reader)? {
+        Format::Binary => Ok(Some(Format::Binary)),
+        _ => {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            Ok(Some(format::layout(&line)))
+        }
+    }
+}
+
+// The recording file. It is rolled over into `<output>.<start of part>` once it exceeds
+// the size or age limit, and reopened under the same name on SIGHUP for logrotate.
+#[derive(Clone)]
+pub struct Output {
+    format: Format,
+    meta: Option<Record>,
+    part: Arc<Mutex<Part>>,
+    rotate_size: Option<u64>,
+    rotate_interval: Option<Duration>,
+}
+
+impl Output {
+    // A JSON recording is appended to. An existing binary recording is moved aside like a
+    // rotated part instead, because a zstd stream cut off by a crash can't be continued.
+    pub fn open(
+        path: &Path,
+        rotate_size: Option<u64>,
+        rotate_interval: Option<Duration>,
+        format: Format,
+        meta: Option<Record>,
+    ) -> io::Result<Self> {
+        match existing_format(path)? {
+            Some(existing) if existing != format => {
+                let name = existing.to_possible_value().unwrap();
+                return Err(io::Error::other(format!(
+                    "{} is a recording in {} format",
+                    path.display(),
+                    name.get_name()
+                )));
+            }
+            Some(Format::Binary) => {
+                let started = format::first_timestamp(path).unwrap_or_else(Utc::now);
+                let rotated = rotated(path, started);
+                fs::rename(path, &rotated)?;
+                rename_index(path, &rotated)?;
+                info!("moved existing recording to {}", rotated.display());
+            }
+            _ => (),
+        }
+        Ok(Self {
+            part: Arc::new(Mutex::new(Part::open(path, format, meta.as_ref())?)),
+            format,
+            meta,
+            rotate_size,
+            rotate_interval,
+        })
+    }
+
+    pub fn reopen(&self) {
+        let mut part = self.part.lock().unwrap();
+        if let Err(e) = part.finish() {
+            error!("could not finish {}: {e:?}", part.path.to_string_lossy());
+        }
+        match Part::open(&part.path, self.format, self.meta.as_ref()) {
+            Ok(reopened) => {
+                *part = reopened;
+                info!("reopened {}", part.path.to_string_lossy());
+            }
+            Err(e) => error!("could not reopen {}: {e:?}", part.path.to_string_lossy()),
+        }
+    }
+
+    fn rotate(&self, part: &mut Part) -> io::Result<()> {
+        part.finish()?;
+        let rotated = rotated(&part.path, part.started);
+        fs::rename(&part.path, &rotated)?;
+        rename_index(&part.path, &rotated)?;
+        info!("rotated recording to {}", rotated.to_string_lossy());
+        *part = Part::open(&part.path, self.format, self.meta.as_ref())?;
+        Ok(())
+    }
+
+    fn is_due(&self, part: &Part) -> bool {
+        let too_large = self.rotate_size.is_some_and(|size| part.size >= size);
+        let too_old = self
+            .rotate_interval
+            .is_some_and(|interval| part.opened.elapsed() >= interval);
+        part.size > 0 && (too_large || too_old)
+    }
+
+    // Writes a single record, which is never split across parts.
+    pub fn write(&self, record: &Record) -> io::Result<()> {
+        let mut part = self.part.lock().unwrap();
+        if self.is_due(&part)
+            && let Err(e) = self.rotate(&mut part)
+        {
+            error!("could not rotate recording: {e:?}");
+        }
+        part.size += part.append(record)?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.part.lock().unwrap().writer.flush()
+    }
+}
+
+// Moves the index along with its part. Parts written before spy kept an index have none.
+fn rename_index(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(index::path(from), index::path(to)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
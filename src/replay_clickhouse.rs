@@ -1,16 +1,8 @@
-use base64::prelude::*;
-use chrono::{DateTime, Utc};
 use clap::{ArgAction, Parser, Subcommand};
 use clickhouse::Client;
-use clickhouse::Row;
-use clickhouse::sql::Identifier;
-use serde::{Deserialize, Serialize};
-use serde_json::from_str;
-use sqlx::{Connection, Executor, PgConnection};
-use std::collections::HashMap;
-use std::fs;
-use std::io::BufRead;
-use std::io::BufReader;
+use prototype::event::Type_;
+use prototype::format::{self, Window};
+use prototype::metrics::{CLICKHOUSE_SCHEMA, UpdateMessage, parse};
 use std::path::{Path, PathBuf};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
@@ -18,15 +10,6 @@ use tracing::{debug, error, info, trace};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{EnvFilter, fmt};
 
-struct RRDMetric {
-    time: i64,
-    path: String,
-    // host: String,
-    // service: String,
-    name: String,
-    value: Option<f64>,
-}
-
 #[derive(Parser, Debug)]
 struct Arguments {
     #[clap(subcommand)]
@@ -40,29 +23,11 @@ enum Command {
     Decipher {
         #[clap(long, short)]
         input: PathBuf,
+        #[clap(flatten)]
+        window: Window,
     },
 }
 
-#[derive(Deserialize, serde::Serialize)]
-#[serde(rename_all = "lowercase")]
-enum Type_ {
-    Send,
-    Recv,
-}
-
-#[derive(Deserialize)]
-struct Fields {
-    type_: Type_,
-    id: u64,
-    message: String,
-}
-
-#[derive(Deserialize)]
-struct Log {
-    timestamp: String,
-    fields: Fields,
-}
-
 fn from_ascii(message: &[u8]) -> Option<&str> {
     if message.iter().all(u8::is_ascii) {
         return std::str::from_utf8(message).ok();
@@ -70,54 +35,36 @@ fn from_ascii(message: &[u8]) -> Option<&str> {
     None
 }
 
-struct UpdateMessage {
-    time: i64,
-    path: String,
-    metrics: Vec<Option<f64>>,
-}
-
-fn parse_float(metric: &str) -> anyhow::Result<Option<f64>> {
-    if metric == "U" {
-        return Ok(None);
-    }
-    Ok(Some(metric.parse()?))
-}
-
-fn parse(message: &str) -> anyhow::Result<UpdateMessage> {
-    let mut parts = message.splitn(3, ' ');
-    let _command = parts.next().unwrap();
-    let path = parts.next().unwrap();
-    let data_str = parts.next().unwrap();
-    let mut data_values = data_str.split(':');
-    let timestamp: i64 = data_values.next().unwrap().parse()?;
-    let metrics: anyhow::Result<Vec<Option<f64>>> =
-        data_values.into_iter().map(parse_float).collect();
-
-    return Ok(UpdateMessage {
-        time: timestamp,
-        path: path.into(),
-        metrics: metrics?,
-    });
-}
-
-async fn decipher(input: &Path, tx: Sender<UpdateMessage>) {
-    let file = fs::File::open(input).unwrap();
+async fn decipher(input: &Path, window: Window, tx: Sender<UpdateMessage>) {
     let mut update_count = 0;
-    for (line, _line_count) in BufReader::new(file).lines().zip(0..) {
-        let log: Log = from_str(&line.unwrap()).unwrap();
-        let bytes = BASE64_STANDARD.decode(&log.fields.message).unwrap();
-        let prompt = match log.fields.type_ {
+    for (record, _line_count) in format::recording(input, window).zip(0..) {
+        let prompt = match record.type_ {
             Type_::Send => ">>",
-            Type_::Recv => continue,
+            Type_::Meta => {
+                if let Some(metadata) = record.metadata() {
+                    info!("{metadata}");
+                }
+                continue;
+            }
+            Type_::Open
+            | Type_::Recv
+            | Type_::Connect
+            | Type_::Error
+            | Type_::Latency
+            | Type_::Fault
+            | Type_::Close => {
+                continue;
+            }
         };
-        trace!("{prompt} connection {}", log.fields.id);
+        let bytes = record.message;
+        trace!("{prompt} connection {}", record.id);
         for message in bytes.split(|&b| b == b'\n') {
             match from_ascii(message) {
                 Some("") => continue,
                 Some(m) => {
                     trace!("{m}");
                     if m.starts_with("UPDATE") {
-                        match parse(m) {
+                        match parse(m, record.timestamp) {
                             Ok(update) => tx.send(update).await.unwrap(),
                             Err(e) => {
                                 error!("Could not parse: {m}, {e}")
@@ -138,45 +85,7 @@ async fn decipher(input: &Path, tx: Sender<UpdateMessage>) {
 
 async fn create_table(client: Client) {
     // name String,
-    let query = client
-        .query(
-            "
-    CREATE TABLE metrics (
-      path String,
-      name UInt64,
-      time DateTime64(3, 'UTC'),
-      value  Nullable(Float64)
-    )
-    ENGINE = MergeTree
-    PRIMARY KEY (path, name, time)
-    ",
-        )
-        .execute()
-        .await
-        .unwrap();
-}
-
-async fn create_partitions(
-    mut rx: Receiver<UpdateMessage>,
-) -> Result<HashMap<String, i32>, sqlx::Error> {
-    let mut seen_metrics = HashMap::new();
-    let mut unique_id = 0;
-
-    while let Some(UpdateMessage {
-        time,
-        path,
-        metrics,
-    }) = rx.recv().await
-    {
-        if !seen_metrics.contains_key(&path) {
-            seen_metrics.insert(path, unique_id);
-            unique_id += 1;
-        }
-    }
-    // We really want to save this instead
-    //  host     TEXT              NOT NULL,
-    //  service  TEXT              NOT NULL,
-    Ok(seen_metrics)
+    client.query(CLICKHOUSE_SCHEMA).execute().await.unwrap();
 }
 
 async fn create_update(
@@ -196,18 +105,8 @@ async fn create_metrics(
     info!("started processing");
     let mut insert = client.insert("metrics").unwrap();
     for (update, i) in updates.iter().zip(0..) {
-        for (metric, i) in update.metrics.iter().zip(0..) {
-            let time =
-                DateTime::<Utc>::from_timestamp(update.time, 0).expect("Invalid UNIX timestamp");
-            insert
-                .write(&MyRow {
-                    path: update.path.clone(),
-                    name: i as u64,
-                    time,
-                    value: *metric,
-                })
-                .await
-                .unwrap();
+        for row in update.rows() {
+            insert.write(&row).await.unwrap();
         }
         if i % 100000 == 0 {
             info!("processing {i}")
@@ -218,42 +117,16 @@ async fn create_metrics(
 }
 
 async fn create_metrics_from_file(
-    metrics: HashMap<String, u64>,
     client: &Client,
     input: &Path,
+    window: Window,
 ) -> Result<(), sqlx::Error> {
     let (tx, rx) = mpsc::channel::<UpdateMessage>(32);
-    let (_send, cons) = tokio::join!(decipher(&input, tx), create_update(rx),);
+    let (_send, cons) = tokio::join!(decipher(input, window, tx), create_update(rx),);
     create_metrics(client, cons).await;
     Ok(())
 }
 
-#[derive(Row, Serialize, Deserialize, Debug)]
-struct MyRow {
-    path: String,
-    name: u64,
-    #[serde(with = "clickhouse::serde::chrono::datetime64::millis")]
-    time: DateTime<Utc>,
-    value: Option<f64>,
-}
-
-async fn create_unique_metrics(mut rx: Receiver<UpdateMessage>) -> HashMap<String, u64> {
-    let mut map = HashMap::new();
-    let mut count = 0;
-    while let Some(UpdateMessage {
-        time,
-        path,
-        metrics,
-    }) = rx.recv().await
-    {
-        map.entry(path).or_insert_with(|| {
-            count += 1;
-            count
-        });
-    }
-    map
-}
-
 #[tokio::main]
 async fn main() -> Result<(), sqlx::Error> {
     let arguments = Arguments::parse();
@@ -267,7 +140,7 @@ async fn main() -> Result<(), sqlx::Error> {
         .with_writer(std::io::stderr)
         .with_filter(filter);
     tracing_subscriber::registry().with(stderr_layer).init();
-    let Command::Decipher { input } = arguments.command;
+    let Command::Decipher { input, window } = arguments.command;
     let client = Client::default()
         // should include both protocol and port
         .with_url("http://localhost:8123")
@@ -275,12 +148,11 @@ async fn main() -> Result<(), sqlx::Error> {
         .with_password("changeme")
         .with_database("default");
 
-    let table_name = "metrics";
     create_table(client.clone()).await;
 
     // let (tx, rx) = mpsc::channel::<UpdateMessage>(32);
     info!("starting");
-    create_metrics_from_file(HashMap::default(), &client, &input)
+    create_metrics_from_file(&client, &input, window)
         .await
         .unwrap();
     Ok(())
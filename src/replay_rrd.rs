@@ -1,13 +1,13 @@
-use base64::prelude::*;
-use chrono::{DateTime, Utc};
 use clap::{ArgAction, Parser, Subcommand};
-use serde::{Deserialize, Serialize};
-use serde_json::{from_str, to_string};
-use sqlx::{Connection, Executor, PgConnection};
+use prototype::event::{DEFAULT_RRD_DIR, Metadata, Type_};
+use prototype::format::{self, Window};
+use prototype::metrics::{UpdateMessage, parse};
+use serde::Serialize;
+use serde_json::to_string;
 use std::collections::HashMap;
 use std::fs;
-use std::io::{BufRead, Write};
-use std::io::{BufReader, BufWriter};
+use std::io::Write;
+use std::io::BufWriter;
 use std::path::{Path, PathBuf};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
@@ -15,14 +15,8 @@ use tracing::{debug, error, info, trace};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{EnvFilter, fmt};
 
-struct RRDMetric {
-    time: i64,
-    path: String,
-    // host: String,
-    // service: String,
-    name: String,
-    value: Option<f64>,
-}
+// Where `iterate.py` creates the RRDs.
+const RRD_DIR: &str = "/tmp/rrd";
 
 #[derive(Parser, Debug)]
 struct Arguments {
@@ -37,29 +31,11 @@ enum Command {
     Decipher {
         #[clap(long, short)]
         input: PathBuf,
+        #[clap(flatten)]
+        window: Window,
     },
 }
 
-#[derive(Deserialize)]
-#[serde(rename_all = "lowercase")]
-enum Type_ {
-    Send,
-    Recv,
-}
-
-#[derive(Deserialize)]
-struct Fields {
-    type_: Type_,
-    id: u64,
-    message: String,
-}
-
-#[derive(Deserialize)]
-struct Log {
-    timestamp: String,
-    fields: Fields,
-}
-
 fn from_ascii(message: &[u8]) -> Option<&str> {
     if message.iter().all(u8::is_ascii) {
         return std::str::from_utf8(message).ok();
@@ -67,56 +43,43 @@ fn from_ascii(message: &[u8]) -> Option<&str> {
     None
 }
 
-#[derive(Serialize)]
-struct UpdateMessage {
-    time: i64,
-    path: String,
-    metrics: Vec<Option<f64>>,
-}
-
-fn parse_float(metric: &str) -> anyhow::Result<Option<f64>> {
-    if metric == "U" {
-        return Ok(None);
-    }
-    Ok(Some(metric.parse()?))
-}
-
-fn parse(message: &str) -> anyhow::Result<UpdateMessage> {
-    let mut parts = message.splitn(3, ' ');
-    let _command = parts.next().unwrap();
-    let path = parts.next().unwrap();
-    let data_str = parts.next().unwrap();
-    let mut data_values = data_str.split(':');
-    let timestamp: i64 = data_values.next().unwrap().parse()?;
-    let metrics: anyhow::Result<Vec<Option<f64>>> =
-        data_values.into_iter().map(parse_float).collect();
-
-    return Ok(UpdateMessage {
-        time: timestamp,
-        path: path.into(),
-        metrics: metrics?,
-    });
-}
-
-async fn decipher(input: &Path, tx: Sender<UpdateMessage>) {
-    let file = fs::File::open(input).unwrap();
+async fn decipher(input: &Path, window: Window, tx: Sender<UpdateMessage>) {
     let mut update_count = 0;
-    for (line, _line_count) in BufReader::new(file).lines().zip(0..) {
-        let log: Log = from_str(&line.unwrap()).unwrap();
-        let bytes = BASE64_STANDARD.decode(&log.fields.message).unwrap();
-        let prompt = match log.fields.type_ {
+    let mut rrd_dir = Some(DEFAULT_RRD_DIR.to_string());
+    for (record, _line_count) in format::recording(input, window).zip(0..) {
+        let prompt = match record.type_ {
             Type_::Send => ">>",
-            Type_::Recv => continue,
+            Type_::Meta => {
+                if let Some(metadata) = record.metadata() {
+                    info!("{metadata}");
+                    rrd_dir = metadata.rrd_dir;
+                }
+                continue;
+            }
+            Type_::Open
+            | Type_::Recv
+            | Type_::Connect
+            | Type_::Error
+            | Type_::Latency
+            | Type_::Fault
+            | Type_::Close => {
+                continue;
+            }
         };
-        trace!("{prompt} connection {}", log.fields.id);
+        let bytes = record.message;
+        trace!("{prompt} connection {}", record.id);
         for message in bytes.split(|&b| b == b'\n') {
             match from_ascii(message) {
                 Some("") => continue,
                 Some(m) => {
                     trace!("{m}");
                     if m.starts_with("UPDATE") {
-                        match parse(m) {
-                            Ok(update) => tx.send(update).await.unwrap(),
+                        match parse(m, record.timestamp) {
+                            Ok(mut update) => {
+                                update.path =
+                                    Metadata::rewrite(rrd_dir.as_deref(), &update.path, RRD_DIR);
+                                tx.send(update).await.unwrap()
+                            }
                             Err(e) => {
                                 error!("Could not parse: {m}, {e}")
                             }
@@ -142,8 +105,8 @@ struct TS {
 
 async fn create_unique_metrics(mut rx: Receiver<UpdateMessage>) -> HashMap<String, u64> {
     let mut map = HashMap::new();
-    while let Some(UpdateMessage{time, path, metrics}) = rx.recv().await {
-        map.entry(path).or_insert_with(|| { metrics.len() as u64 });
+    while let Some(UpdateMessage { path, metrics, .. }) = rx.recv().await {
+        map.entry(path).or_insert_with(|| metrics.len() as u64);
     }
     map
 }
@@ -161,10 +124,10 @@ async fn main() -> Result<(), sqlx::Error> {
         .with_writer(std::io::stderr)
         .with_filter(filter);
     tracing_subscriber::registry().with(stderr_layer).init();
-    let Command::Decipher { input } = arguments.command;
+    let Command::Decipher { input, window } = arguments.command;
 
     let (tx, rx) = mpsc::channel::<UpdateMessage>(32);
-    let (_send, cons) = tokio::join!(decipher(&input, tx), create_unique_metrics(rx));
+    let (_send, cons) = tokio::join!(decipher(&input, window, tx), create_unique_metrics(rx));
     info!("finished partition");
     let file = fs::File::create("/tmp/metrics").unwrap();
     let mut writer = BufWriter::new(file);
@@ -0,0 +1,324 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::sync::Notify;
+use tokio::sync::futures::Notified;
+use tracing::{error, info};
+
+use crate::net::Stream;
+
+const UNAVAILABLE: &str = "rrdcached unavailable, spy is spooling updates";
+
+// Spooled updates are replayed in BATCHes of this many lines.
+const DRAIN_BATCH: usize = 1000;
+
+// UPDATE lines, which were answered by spy while the upstream was down. The file contains
+// one command per line. For replaying, it is moved to `<spool>.draining`, so that clients
+// still being answered by spy append to a new one in the meantime. How much of it has been
+// replayed so far is kept in `<spool>.done`, so a connection lost while draining only repeats
+// the last BATCH. Those updates may reach rrdcached twice.
+pub struct Spool {
+    path: PathBuf,
+    appended: Mutex<Appended>,
+    draining: tokio::sync::Mutex<()>,
+    drained: Notify,
+}
+
+struct Appended {
+    file: fs::File,
+    len: usize,
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+fn open_file(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+}
+
+impl Spool {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = open_file(path)?;
+        let len = BufReader::new(&file).lines().count();
+        if len > 0 {
+            info!("spool {} contains {len} update(s)", path.to_string_lossy());
+        }
+        let draining = with_suffix(path, ".draining");
+        if draining.exists() {
+            info!("spool {} is partly replayed", draining.to_string_lossy());
+        }
+        Ok(Self {
+            path: path.into(),
+            appended: Mutex::new(Appended { file, len }),
+            draining: tokio::sync::Mutex::default(),
+            drained: Notify::new(),
+        })
+    }
+
+    fn appended(&self) -> std::sync::MutexGuard<'_, Appended> {
+        self.appended.lock().unwrap()
+    }
+
+    pub fn append(&self, updates: &[String]) -> io::Result<()> {
+        if updates.is_empty() {
+            return Ok(());
+        }
+        let mut content = updates.join("\n");
+        content.push('\n');
+        let mut appended = self.appended();
+        appended.file.write_all(content.as_bytes())?;
+        appended.file.sync_data()?;
+        appended.len += updates.len();
+        Ok(())
+    }
+
+    // Completes once a drain has emptied the spool.
+    pub fn drained(&self) -> Notified<'_> {
+        self.drained.notified()
+    }
+
+    // Replays the spool, including what is appended while draining, until it is empty. A
+    // single connection drains at a time, the others wait until it is done, so that none of
+    // them forwards updates ahead of spooled ones.
+    pub async fn drain(&self, stream: &mut Stream) -> io::Result<usize> {
+        let _draining = self.draining.lock().await;
+        let draining = with_suffix(&self.path, ".draining");
+        let progress = with_suffix(&self.path, ".done");
+        let mut reader = tokio::io::BufReader::new(stream);
+        let (mut drained, mut errors) = (0, 0);
+        loop {
+            if !draining.exists() {
+                let mut appended = self.appended();
+                if appended.len == 0 {
+                    break;
+                }
+                remove_if_exists(&progress)?;
+                fs::rename(&self.path, &draining)?;
+                appended.file = open_file(&self.path)?;
+                appended.len = 0;
+            }
+            let (replayed, failed) = replay_file(&mut reader, &draining, &progress).await?;
+            drained += replayed;
+            errors += failed;
+        }
+        self.drained.notify_waiters();
+        if drained > 0 {
+            info!("drained {drained} update(s) from spool, {errors} error(s)");
+        }
+        Ok(drained)
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Replays a spool file in BATCHes, so rrdcached does not answer each line. Progress is saved
+// after each BATCH has been answered. Returns the number of replayed and failed updates.
+async fn replay_file(
+    reader: &mut tokio::io::BufReader<&mut Stream>,
+    path: &Path,
+    progress: &Path,
+) -> io::Result<(usize, usize)> {
+    let content = fs::read(path)?;
+    let mut offset = fs::read_to_string(progress)
+        .ok()
+        .and_then(|offset| offset.trim().parse().ok())
+        .unwrap_or(0)
+        .min(content.len());
+    let lines: Vec<&[u8]> = content[offset..].split_inclusive(|&b| b == b'\n').collect();
+    info!("draining {} update(s) from spool", lines.len());
+    let mut errors = 0;
+    for batch in lines.chunks(DRAIN_BATCH) {
+        errors += replay(reader, &batch.concat()).await?;
+        offset += batch.iter().map(|line| line.len()).sum::<usize>();
+        let mut file = fs::File::create(progress)?;
+        file.write_all(offset.to_string().as_bytes())?;
+        file.sync_data()?;
+    }
+    // Without the progress, a crash in between replays the file again instead of skipping
+    // updates of the next one.
+    remove_if_exists(progress)?;
+    fs::remove_file(path)?;
+    Ok((lines.len(), errors))
+}
+
+// Sends updates as a single BATCH and returns the number of failed ones, which are logged.
+async fn replay(
+    reader: &mut tokio::io::BufReader<&mut Stream>,
+    updates: &[u8],
+) -> io::Result<usize> {
+    let mut status = String::new();
+    reader.get_mut().write_all(b"BATCH\n").await?;
+    reader.read_line(&mut status).await?;
+    if !status.starts_with('0') {
+        return Err(io::Error::other(format!(
+            "BATCH rejected: {}",
+            status.trim_end()
+        )));
+    }
+    reader.get_mut().write_all(updates).await?;
+    reader.get_mut().write_all(b".\n").await?;
+    status.clear();
+    reader.read_line(&mut status).await?;
+    let errors: usize = status
+        .split(' ')
+        .next()
+        .and_then(|count| count.parse().ok())
+        .ok_or_else(|| io::Error::other(format!("bad BATCH status: {}", status.trim_end())))?;
+    for _ in 0..errors {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        error!("spooled update failed: {}", line.trim_end());
+    }
+    Ok(errors)
+}
+
+// Answers client commands in place of rrdcached. Only complete lines are consumed, so any
+// remaining partial command can be handed to the upstream once it is back.
+#[derive(Default)]
+pub struct Spooler {
+    pub pending: Vec<u8>,
+    batch: Option<Vec<String>>,
+    batch_line: usize,
+}
+
+#[derive(Default)]
+pub struct Reply {
+    pub updates: Vec<String>,
+    pub response: Vec<u8>,
+    pub quit: bool,
+}
+
+impl Spooler {
+    pub fn in_batch(&self) -> bool {
+        self.batch.is_some()
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) -> Reply {
+        self.pending.extend_from_slice(bytes);
+        let mut reply = Reply::default();
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..end]).trim_end().to_string();
+            if self.answer(line, &mut reply) {
+                reply.quit = true;
+                break;
+            }
+        }
+        reply
+    }
+
+    fn answer(&mut self, line: String, reply: &mut Reply) -> bool {
+        let command = line
+            .split(' ')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        if let Some(errors) = &mut self.batch {
+            self.batch_line += 1;
+            if line == "." {
+                let mut response = format!("{} errors\n", errors.len());
+                for error in errors.iter() {
+                    response += error;
+                    response.push('\n');
+                }
+                reply.response.extend_from_slice(response.as_bytes());
+                self.batch = None;
+            } else if command == "UPDATE" {
+                reply.updates.push(line);
+            } else {
+                errors.push(format!("{} {UNAVAILABLE}", self.batch_line));
+            }
+            return false;
+        }
+        let response = match command.as_str() {
+            "UPDATE" => {
+                let values = line.split_whitespace().count().saturating_sub(2);
+                reply.updates.push(line);
+                format!("0 errors, enqueued {values} value(s).\n")
+            }
+            "BATCH" => {
+                self.batch = Some(Vec::new());
+                self.batch_line = 0;
+                "0 Go ahead.  End with dot '.' on its own line.\n".to_string()
+            }
+            "QUIT" => return true,
+            "" => return false,
+            _ => format!("-1 {UNAVAILABLE}\n"),
+        };
+        reply.response.extend_from_slice(response.as_bytes());
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(reply: &Reply) -> &str {
+        std::str::from_utf8(&reply.response).unwrap()
+    }
+
+    #[test]
+    fn updates_are_answered_and_spooled() {
+        let mut spooler = Spooler::default();
+        let reply = spooler.feed(b"UPDATE /a.rrd 1:2:3\nSTATS\n\n");
+        assert_eq!(reply.updates, ["UPDATE /a.rrd 1:2:3"]);
+        assert_eq!(
+            text(&reply),
+            format!("0 errors, enqueued 1 value(s).\n-1 {UNAVAILABLE}\n")
+        );
+        assert!(!reply.quit);
+    }
+
+    #[test]
+    fn batch_errors_are_numbered() {
+        let mut spooler = Spooler::default();
+        let reply = spooler.feed(b"BATCH\nUPDATE /a.rrd 1:1\nFLUSH /a.rrd\n");
+        assert!(spooler.in_batch());
+        assert_eq!(reply.updates, ["UPDATE /a.rrd 1:1"]);
+        assert_eq!(
+            text(&reply),
+            "0 Go ahead.  End with dot '.' on its own line.\n"
+        );
+        let reply = spooler.feed(b"update /b.rrd 2:2\nFORGET /b.rrd\n.\n");
+        assert!(!spooler.in_batch());
+        assert_eq!(reply.updates, ["update /b.rrd 2:2"]);
+        assert_eq!(
+            text(&reply),
+            format!("2 errors\n2 {UNAVAILABLE}\n4 {UNAVAILABLE}\n")
+        );
+    }
+
+    #[test]
+    fn quit_ends_the_session() {
+        let mut spooler = Spooler::default();
+        let reply = spooler.feed(b"UPDATE /a.rrd 1:1\nQUIT\nUPDATE /b.rrd 2:2\n");
+        assert!(reply.quit);
+        assert_eq!(reply.updates, ["UPDATE /a.rrd 1:1"]);
+    }
+
+    #[test]
+    fn partial_lines_are_kept() {
+        let mut spooler = Spooler::default();
+        let reply = spooler.feed(b"UPDATE /a.rrd 1:1\nUPDATE /b.r");
+        assert_eq!(reply.updates, ["UPDATE /a.rrd 1:1"]);
+        assert_eq!(spooler.pending, b"UPDATE /b.r");
+        let reply = spooler.feed(b"rd 2:2\r\n");
+        assert_eq!(reply.updates, ["UPDATE /b.rrd 2:2"]);
+        assert!(spooler.pending.is_empty());
+    }
+}
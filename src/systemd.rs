@@ -0,0 +1,78 @@
+use std::env;
+use std::io;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::Duration;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
+
+use crate::net::Listener;
+
+const LISTEN_FDS_START: RawFd = 3;
+
+fn for_us(pid: &str) -> bool {
+    env::var(pid).is_ok_and(|pid| pid == process::id().to_string())
+}
+
+// The socket passed by systemd socket activation, if spy was started that way.
+pub fn listener() -> Option<io::Result<Listener>> {
+    let fds: RawFd = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if !for_us("LISTEN_PID") || fds < 1 {
+        return None;
+    }
+    if fds > 1 {
+        warn!(
+            "ignoring {} additional socket(s) passed by systemd",
+            fds - 1
+        );
+    }
+    // SAFETY: systemd passes the sockets starting at fd 3, and nothing else in spy uses them.
+    let fd = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) };
+    Some(Listener::from_fd(fd))
+}
+
+fn send(socket: &std::ffi::OsStr, state: &str) -> io::Result<()> {
+    let addr = match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+// Reports the state to the service manager, if spy runs as a `Type=notify` service.
+pub fn notify(state: &str) {
+    let Some(socket) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    debug!("notifying systemd of {}", state.replace('\n', ", "));
+    if let Err(e) = send(&socket, state) {
+        error!("could not notify systemd: {e:?}");
+    }
+}
+
+// Pings the watchdog at half the `WatchdogSec=` interval. The pings are sent from the runtime,
+// so a stuck runtime gets spy restarted.
+pub async fn watchdog(token: CancellationToken) {
+    let usec: Option<u64> = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok());
+    // Below 2 µs, there is no interval to ping at, i.e., no watchdog.
+    let Some(usec) = usec.filter(|&usec| usec >= 2) else {
+        return;
+    };
+    if env::var("WATCHDOG_PID").is_ok() && !for_us("WATCHDOG_PID") {
+        return;
+    }
+    let mut interval = interval(Duration::from_micros(usec / 2));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => notify("WATCHDOG=1"),
+            _ = token.cancelled() => break,
+        }
+    }
+}
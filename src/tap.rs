@@ -0,0 +1,135 @@
+use glob::Pattern;
+use std::sync::Arc;
+use tokio::time::Instant;
+
+use crate::Proxy;
+use crate::control::Traffic;
+use crate::protocol::{Pending, Request, Session};
+use crate::recording::Type_;
+
+// Selects the commands, which are recorded. Everything else is still forwarded.
+pub struct Filter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    paths: Vec<Pattern>,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String], paths: &[Pattern]) -> Option<Self> {
+        if include.is_empty() && exclude.is_empty() && paths.is_empty() {
+            return None;
+        }
+        let upper = |commands: &[String]| commands.iter().map(|c| c.to_ascii_uppercase()).collect();
+        Some(Self {
+            include: upper(include),
+            exclude: upper(exclude),
+            paths: paths.to_vec(),
+        })
+    }
+
+    fn matches(&self, request: &Request) -> bool {
+        let included = self.include.is_empty() || self.include.contains(&request.command);
+        let excluded = self.exclude.contains(&request.command);
+        let path = self.paths.is_empty()
+            || request
+                .path
+                .as_ref()
+                .is_some_and(|path| self.paths.iter().any(|p| p.matches(path)));
+        included && !excluded && path
+    }
+}
+
+// Hands the traffic of a single connection to the recorder and the stats, and UPDATEs to the
+// tee. Without a filter, chunks are recorded as they are read. Otherwise only complete lines of
+// matching requests and the responses to them are recorded. Each request is paired with its
+// response, and the time in between is recorded as the latency of the command.
+pub struct Tap<'a> {
+    id: u64,
+    proxy: &'a Proxy,
+    traffic: Arc<Traffic>,
+    session: Session,
+}
+
+impl<'a> Tap<'a> {
+    pub fn new(id: u64, proxy: &'a Proxy) -> Self {
+        Self {
+            id,
+            proxy,
+            traffic: proxy.stats.traffic(id),
+            session: Session::default(),
+        }
+    }
+
+    pub fn send(&mut self, bytes: &[u8]) {
+        let recorder = &self.proxy.recorder;
+        let filter = self.proxy.filter.as_ref();
+        let sent = Instant::now();
+        let mut recorded = Vec::new();
+        self.traffic.sent(bytes.len());
+        for request in self.session.requests(bytes) {
+            self.proxy
+                .stats
+                .command(&self.traffic, &request.command, request.line.len());
+            let matches = filter.is_none_or(|filter| filter.matches(&request));
+            if let Some(tee) = &self.proxy.tee
+                && request.command == "UPDATE"
+            {
+                tee.update(&request.line);
+            }
+            if request.answered {
+                self.session.expect(Pending {
+                    command: request.command,
+                    sent,
+                    recorded: matches,
+                });
+            }
+            if matches {
+                recorded.extend_from_slice(&request.line);
+            }
+        }
+        if filter.is_none() || bytes.is_empty() {
+            recorder.bytes(Type_::Send, self.id, bytes);
+        } else if !recorded.is_empty() {
+            recorder.bytes(Type_::Send, self.id, &recorded);
+        }
+    }
+
+    pub fn recv(&mut self, bytes: &[u8]) {
+        self.receive(bytes, true);
+    }
+
+    // Takes the answers spy gives in place of the upstream, e.g., while spooling. They tell
+    // nothing about rrdcached, so no latency is recorded for them.
+    pub fn answered(&mut self, bytes: &[u8]) {
+        self.receive(bytes, false);
+    }
+
+    fn receive(&mut self, bytes: &[u8], timed: bool) {
+        let recorder = &self.proxy.recorder;
+        let filter = self.proxy.filter.as_ref();
+        let received = Instant::now();
+        let mut recorded = Vec::new();
+        let mut latencies = Vec::new();
+        self.traffic.received(bytes.len());
+        for response in self.session.responses(bytes) {
+            match response.request {
+                Some(request) if request.recorded => {
+                    recorded.extend_from_slice(&response.bytes);
+                    if timed {
+                        latencies.push((request.command, received - request.sent));
+                    }
+                }
+                Some(_) => (),
+                None => recorded.extend_from_slice(&response.bytes),
+            }
+        }
+        if filter.is_none() || bytes.is_empty() {
+            recorder.bytes(Type_::Recv, self.id, bytes);
+        } else if !recorded.is_empty() {
+            recorder.bytes(Type_::Recv, self.id, &recorded);
+        }
+        for (command, latency) in latencies {
+            recorder.latency(self.id, command, latency);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use chrono::Utc;
+use clickhouse::Client;
+use prototype::metrics::{CLICKHOUSE_SCHEMA, POSTGRES_SCHEMA, UpdateMessage, parse};
+use sqlx::{Connection, Executor, PgConnection};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Receiver, Sender, channel};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+const BATCH_SIZE: usize = 4096;
+
+// A secondary backend, which receives a copy of the forwarded UPDATEs. ClickHouse is addressed
+// by `http(s)://user:password@host:port/database`, Postgres by its connection string.
+#[derive(Debug, Clone)]
+pub enum Sink {
+    ClickHouse(Url),
+    Postgres(Url),
+}
+
+impl FromStr for Sink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|e| format!("{e}"))?;
+        match url.scheme() {
+            "http" | "https" => Ok(Self::ClickHouse(url)),
+            "postgres" | "postgresql" => Ok(Self::Postgres(url)),
+            scheme => Err(format!(
+                "unknown scheme {scheme}, expected http, https or postgres"
+            )),
+        }
+    }
+}
+
+// Shows the sink without its password.
+impl fmt::Display for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (Self::ClickHouse(url) | Self::Postgres(url)) = self;
+        let mut url = url.clone();
+        url.set_password(None).ok();
+        write!(f, "{url}")
+    }
+}
+
+enum Writer {
+    ClickHouse {
+        client: Box<Client>,
+        created: bool,
+    },
+    Postgres {
+        url: Url,
+        connection: Option<PgConnection>,
+    },
+}
+
+impl Writer {
+    fn new(sink: Sink) -> Self {
+        match sink {
+            Sink::ClickHouse(url) => {
+                let database = url.path().trim_matches('/');
+                let mut base = url.clone();
+                base.set_username("").ok();
+                base.set_password(None).ok();
+                let mut client = Client::default()
+                    .with_url(&base[..url::Position::BeforePath])
+                    .with_user(match url.username() {
+                        "" => "default",
+                        user => user,
+                    })
+                    .with_database(if database.is_empty() {
+                        "default"
+                    } else {
+                        database
+                    });
+                if let Some(password) = url.password() {
+                    client = client.with_password(password);
+                }
+                Self::ClickHouse {
+                    client: Box::new(client),
+                    created: false,
+                }
+            }
+            Sink::Postgres(url) => Self::Postgres {
+                url,
+                connection: None,
+            },
+        }
+    }
+
+    async fn write(&mut self, updates: &[UpdateMessage]) -> anyhow::Result<()> {
+        match self {
+            Self::ClickHouse { client, created } => {
+                if !*created {
+                    client.query(CLICKHOUSE_SCHEMA).execute().await?;
+                    *created = true;
+                }
+                let mut insert = client.insert("metrics")?;
+                for update in updates {
+                    for row in update.rows() {
+                        insert.write(&row).await?;
+                    }
+                }
+                insert.end().await?;
+            }
+            Self::Postgres { url, connection } => {
+                if connection.is_none() {
+                    let mut conn = PgConnection::connect(url.as_str()).await?;
+                    conn.execute(POSTGRES_SCHEMA).await?;
+                    *connection = Some(conn);
+                }
+                let values: Vec<String> = updates
+                    .iter()
+                    .flat_map(|update| update.values(partition(&update.path)))
+                    .collect();
+                let query_str = format!(
+                    "
+                    INSERT INTO metrics (partition, name, time, value)
+                      VALUES {}
+                      ON CONFLICT DO NOTHING
+                    ",
+                    values.join(",")
+                );
+                let conn = connection.as_mut().unwrap();
+                if let Err(e) = conn.execute(sqlx::query(&query_str)).await {
+                    *connection = None;
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Unlike `replay`, which numbers the paths of a finished recording, a live site needs a
+// partition, which stays the same across restarts. This is the 64-bit FNV-1a hash of the path.
+fn partition(path: &str) -> i64 {
+    let hash = path.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    (hash >> 1) as i64
+}
+
+// Parses UPDATEs as they pass and writes them to the sink in the background. Like the
+// recorder, it never slows down forwarding: updates are dropped if the sink can't keep up.
+pub struct Tee {
+    tx: Sender<UpdateMessage>,
+    dropped: AtomicU64,
+}
+
+impl Tee {
+    pub fn spawn(sink: Sink, capacity: usize) -> (Self, JoinHandle<()>) {
+        let (tx, rx) = channel(capacity);
+        let handle = tokio::spawn(write_updates(rx, Writer::new(sink)));
+        let tee = Self {
+            tx,
+            dropped: AtomicU64::new(0),
+        };
+        (tee, handle)
+    }
+
+    pub fn update(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let update = match parse(&line, Utc::now()) {
+            Ok(update) => update,
+            Err(e) => {
+                debug!("could not parse {}: {e}", line.trim_end());
+                return;
+            }
+        };
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(update) {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("tee queue is full, dropped {dropped} update(s) so far");
+            }
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+async fn write_updates(mut rx: Receiver<UpdateMessage>, mut writer: Writer) {
+    let mut updates = Vec::with_capacity(BATCH_SIZE);
+    while rx.recv_many(&mut updates, BATCH_SIZE).await > 0 {
+        if let Err(e) = writer.write(&updates).await {
+            error!("could not tee {} update(s): {e:#}", updates.len());
+        }
+        updates.clear();
+    }
+    info!("tee stopped");
+}